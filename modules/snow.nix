@@ -1,4 +1,4 @@
-{ lib, ... }:
+{ config, lib, options, ... }:
 with lib;
 {
   options.snow = {
@@ -55,6 +55,45 @@ with lib;
       '';
     };
 
+    sshUser = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        The user to log in as when connecting to the target host.
+        Ignored if `targetHost` already contains a `user@` prefix
+      '';
+    };
+
+    sshIdentityFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        Path to the SSH private key to use when connecting to the target host
+      '';
+    };
+
+    sshJumpHost = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        Bastion host through which the target host is reached (ProxyJump).
+        Accepts everything `ssh -J` does, e.g. `user@bastion:2222`
+      '';
+    };
+
+    sshOptions = mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      example = [
+        "ServerAliveInterval=30"
+        "Compression=yes"
+      ];
+      description = ''
+        Additional SSH options, each passed as `-o <option>` when connecting
+        to the target host
+      '';
+    };
+
     buildHost = mkOption {
       type = types.nullOr types.str;
       default = null;
@@ -73,16 +112,218 @@ with lib;
       '';
     };
 
+    facterReport = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = literalExpression "./facter.json";
+      description = ''
+        Hardware report generated by `nixos-facter`, e.g. through
+        `snow assimilate --hardware-report facter`. Passed on to
+        `hardware.facter.reportPath`, or `facter.reportPath` of
+        nixos-facter-modules
+      '';
+    };
+
     vm.id = mkOption {
       type = types.nullOr types.int;
       default = null;
-      description = "ID the VM should have";
+      description = ''
+        ID the VM should have on Proxmox. If unset, `snow provision` picks a
+        free one from the `vmPools.ids` of the flake's `snow` output. libvirt
+        domains are named after the nixosConfiguration instead
+      '';
     };
 
     vm.ip = mkOption {
       type = types.nullOr types.str;
       default = null;
-      description = "IP address the VM should have";
+      description = ''
+        IP address the VM should have. If unset, `snow provision` picks a free
+        one from the `vmPools.ips` of the flake's `snow` output
+      '';
+    };
+
+    vm.allocationFile = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = literalExpression "./vms/allocations.json";
+      # Kept as a string, so evaluating the snow config neither copies the file
+      # nor requires it to exist before the first allocation
+      apply = file: if file == null then null else toString file;
+      description = ''
+        File in which `snow provision` records allocated VM ids and IP
+        addresses, keyed by `allocationName`. Values found for this host are
+        used as defaults for `id` and `ip`. Has to lie within the flake
+      '';
+    };
+
+    vm.allocationName = mkOption {
+      type = types.str;
+      default = config.networking.hostName;
+      defaultText = literalExpression "config.networking.hostName";
+      description = ''
+        Key of this host in `allocationFile`. Set it to the name of the
+        nixosConfiguration if that differs from the host name, to keep
+        allocations apart for configurations sharing one
+      '';
+    };
+
+    vm.kind = mkOption {
+      type = types.enum [
+        "qemu"
+        "lxc"
+      ];
+      default = "qemu";
+      description = ''
+        Whether to create a VM, or a container from the `proxmox-lxc` image of
+        the nixosConfiguration using `pct create`. Containers get their address
+        from `ip`, `prefixLength`, `gateway` and `nameservers`, and are only
+        supported by the `ssh` and `api` backends
+      '';
+    };
+
+    vm.source = mkOption {
+      type = types.enum [
+        "image"
+        "template"
+      ];
+      default = "image";
+      description = ''
+        How the VM is created. `image` builds `system.build.images` for the
+        nixosConfiguration and restores it, `template` clones the Proxmox
+        template `templateId`, configures it through cloud-init and deploys
+        the nixosConfiguration onto it afterwards. The template has to accept
+        SSH logins from the machine running snow, and must not contain SSH host
+        keys, so every clone generates its own
+      '';
+    };
+
+    vm.templateId = mkOption {
+      type = types.nullOr types.int;
+      default = null;
+      description = "ID of the Proxmox template VM to clone if `source` is `template`";
+    };
+
+    vm.prefixLength = mkOption {
+      type = types.ints.between 0 128;
+      default = 24;
+      description = "Prefix length of `ip`, passed to cloud-init for cloned templates, and to containers";
+    };
+
+    vm.gateway = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = "Default gateway passed to cloud-init for cloned templates, and to containers";
+    };
+
+    vm.nameservers = mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      description = "DNS servers passed to cloud-init for cloned templates, and to containers";
+    };
+
+    vm.backend = mkOption {
+      type = types.enum [
+        "ssh"
+        "api"
+        "libvirt"
+      ];
+      default = "ssh";
+      description = ''
+        Where and how the VM is created. `ssh` runs `qm` on `proxmoxHost` and
+        copies images via `proxmoxImageStore`, `api` uses the Proxmox VE HTTP API
+        configured in `vm.api`, `libvirt` defines a domain through `virsh` as
+        configured in `vm.libvirt`
+      '';
+    };
+
+    vm.libvirt.uri = mkOption {
+      type = types.str;
+      default = "qemu:///system";
+      example = "qemu+ssh://root@workstation/system";
+      description = "libvirt connection URI the domain is defined on";
+    };
+
+    vm.libvirt.host = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        SSH host the libvirt daemon runs on, used to copy the disk image there.
+        The image is copied locally if unset
+      '';
+    };
+
+    vm.libvirt.imageDir = mkOption {
+      type = types.str;
+      default = "/var/lib/libvirt/images";
+      description = "Directory on the libvirt host the disk image is placed in";
+    };
+
+    vm.libvirt.network = mkOption {
+      type = types.str;
+      default = "network=default";
+      example = "bridge=br0";
+      description = "Network the domain is attached to, in `virt-install --network` syntax";
+    };
+
+    vm.libvirt.osVariant = mkOption {
+      type = types.str;
+      default = "nixos-unstable";
+      description = "OS variant passed to `virt-install`";
+    };
+
+    vm.api.url = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "https://pve.example.org:8006";
+      description = "URL of the Proxmox VE API";
+    };
+
+    vm.api.node = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = "Proxmox node on which the VM is created";
+    };
+
+    vm.api.storage = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        Storage to which the VM image is uploaded. Must allow VZDump backups
+        for VMs, and container templates for containers
+      '';
+    };
+
+    vm.api.sshHost = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      defaultText = literalExpression ''"root@<host of vm.api.url>"'';
+      description = ''
+        Host to which VM images are copied with scp, as the API only accepts
+        uploads of ISO images and container templates
+      '';
+    };
+
+    vm.api.tokenId = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "snow@pve!provision";
+      description = "ID of the API token used to authenticate";
+    };
+
+    vm.api.tokenFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        Path to a file on the machine running snow which contains the API
+        token secret. `SNOW_PROXMOX_API_TOKEN` takes precedence if set
+      '';
+    };
+
+    vm.api.insecure = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Whether to skip TLS certificate verification, e.g. for self-signed certificates";
     };
 
     vm.proxmoxHost = mkOption {
@@ -102,5 +343,142 @@ with lib;
       default = null;
       description = "Amount of GiB to which the disk size will be increased upon VM creation";
     };
+
+    vm.cores = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Number of CPU cores per socket. Left as is if unset";
+    };
+
+    vm.sockets = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Number of CPU sockets. Left as is if unset";
+    };
+
+    vm.memory = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Amount of memory in MiB. Left as is if unset";
+    };
+
+    vm.network.bridge = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "vmbr0";
+      description = "Bridge the first network interface is attached to. Left as is if unset";
+    };
+
+    vm.network.vlan = mkOption {
+      type = types.nullOr types.ints.u16;
+      default = null;
+      description = "VLAN tag of the first network interface. Left as is if unset";
+    };
+
+    vm.network.mac = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "BC:24:11:00:00:01";
+      description = "MAC address of the first network interface. Left as is if unset";
+    };
+
+    vm.tags = mkOption {
+      type = types.nullOr (types.listOf types.str);
+      default = null;
+      description = "Tags shown for the VM in the proxmox UI. Left as is if unset";
+    };
+
+    vm.onboot = mkOption {
+      type = types.nullOr types.bool;
+      default = null;
+      description = "Whether the VM is started when the proxmox host boots. Left as is if unset";
+    };
+
+    vm.remoteImageDir = mkOption {
+      type = types.str;
+      default = "/mnt/pve/proxmox_images/template/iso";
+      description = ''
+        Directory on the proxmox host under which the contents of
+        `proxmoxImageStore` are available. Only used by the `ssh` backend
+      '';
+    };
+
+    vm.targetStorage = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "vm_datastore";
+      description = ''
+        Proxmox storage on which the disks of the restored VM are created.
+        Uses the storage recorded in the image if unset
+      '';
+    };
+
+    vm.disk = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      defaultText = literalExpression ''"virtio0", or "rootfs" for containers'';
+      description = "Bus and slot of the VM disk to resize, as named by proxmox";
+    };
+
+    vm.rootPartition = mkOption {
+      type = types.str;
+      default = "/dev/vda2";
+      description = "Root partition inside the VM, grown with `resize2fs` after resizing the disk";
+    };
+
+    vm.injectHostKey = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether `snow provision` generates the SSH host key locally and passes
+        it to the VM on first boot through a qemu fw_cfg entry. Secrets then
+        work right away, without rekeying after the first boot.
+        Not supported by the `api` backend
+      '';
+    };
   };
+
+  config = mkMerge [
+    # Only defined if a facter module is available, as defining unknown options fails
+    (optionalAttrs (options.hardware ? facter) {
+      hardware.facter.reportPath = mkIf (config.snow.facterReport != null) config.snow.facterReport;
+    })
+    (optionalAttrs (options ? facter) {
+      facter.reportPath = mkIf (config.snow.facterReport != null) config.snow.facterReport;
+    })
+
+    (mkIf (config.snow.vm.allocationFile != null) (
+      let
+        file = config.snow.vm.allocationFile;
+        allocations = if builtins.pathExists file then builtins.fromJSON (builtins.readFile file) else { };
+        allocation = allocations.${config.snow.vm.allocationName} or { };
+      in
+      {
+        snow.vm.id = mkIf (allocation ? id) (mkDefault allocation.id);
+        snow.vm.ip = mkIf (allocation ? ip) (mkDefault allocation.ip);
+      }
+    ))
+
+    (mkIf config.snow.vm.injectHostKey {
+      boot.initrd.kernelModules = [ "qemu_fw_cfg" ];
+
+      system.activationScripts = {
+        snowHostKey = {
+          deps = [ "specialfs" ];
+          text = ''
+            key=/sys/firmware/qemu_fw_cfg/by_name/opt/io.snow/ssh_host_ed25519_key/raw
+            if [ -e "$key" ] && [ ! -e /etc/ssh/ssh_host_ed25519_key ]; then
+              mkdir -p /etc/ssh
+              (umask 077; cat "$key" > /etc/ssh/ssh_host_ed25519_key)
+            fi
+          '';
+        };
+      }
+      # Install the host key before agenix tries to decrypt secrets with it. Only with agenix, as
+      # depending on a missing activation script fails the build.
+      // optionalAttrs (options ? age) {
+        agenixNewGeneration.deps = [ "snowHostKey" ];
+      };
+    })
+  ];
 }
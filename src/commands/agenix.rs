@@ -1,9 +1,253 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
 use crate::{
     commands::{exist_untracked_secrets, git_add},
     util::Result,
 };
 
 use super::runners::SnowCommand;
+use super::util::{
+    DUMMY_PUBKEY, read_from_repl, read_public_key, read_stanzas, recipient_problems, repo_path,
+    secret_files,
+};
+
+/// Collect what agenix-rekey knows about each host: the keys it encrypts for and the secrets
+/// with both their master-encrypted `rekeyFile` and the rekeyed `file`.
+const AGE_CONFIG_NIX: &str = r#"hosts: builtins.mapAttrs (_: host:
+  let
+    age = host.config.age or { };
+    rekey = age.rekey or { };
+    read = key: if builtins.isPath key then builtins.readFile key else key;
+    path = x: if x == null then null else toString x;
+    storageMode = rekey.storageMode or null;
+  in
+  {
+    hostPubkey = if rekey ? hostPubkey then read rekey.hostPubkey else null;
+    masterIdentities = map (x:
+      if builtins.isAttrs x
+      then { identity = toString x.identity; pubkey = x.pubkey or null; }
+      else { identity = toString x; pubkey = null; }
+    ) (rekey.masterIdentities or [ ]);
+    extraEncryptionPubkeys = map read (rekey.extraEncryptionPubkeys or [ ]);
+    localStorageDir = if storageMode == "local" then path rekey.localStorageDir else null;
+    secrets = builtins.mapAttrs (_: secret: {
+      rekeyFile = path (secret.rekeyFile or null);
+      file = path (secret.file or null);
+    }) (age.secrets or { });
+  }) hosts"#;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AgeConfig {
+    host_pubkey: Option<String>,
+    master_identities: Vec<MasterIdentity>,
+    extra_encryption_pubkeys: Vec<String>,
+    local_storage_dir: Option<String>,
+    secrets: BTreeMap<String, AgeSecret>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MasterIdentity {
+    identity: String,
+    pubkey: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AgeSecret {
+    rekey_file: Option<String>,
+    file: Option<String>,
+}
+
+/// The public key of a master identity: given explicitly, noted in the identity file the way
+/// `age-keygen` and `age-plugin-yubikey` do, or stored next to an ssh identity.
+fn master_pubkey(identity: &MasterIdentity) -> Option<String> {
+    if let Some(ref pubkey) = identity.pubkey {
+        return Some(pubkey.trim().to_string());
+    }
+    let path = repo_path(&identity.identity);
+    let noted = std::fs::read_to_string(&path).ok().and_then(|content| {
+        content.lines().find_map(|line| {
+            let (_, key) = line
+                .split_once("public key:")
+                .or_else(|| line.split_once("Recipient:"))?;
+            Some(key.trim().to_string())
+        })
+    });
+    noted.or_else(|| read_public_key(Path::new(&format!("{}.pub", path.display()))))
+}
+
+/// Check the recipients of the rekeyed copy of a secret against the host key, and whether the
+/// host still encrypts for the dummy key.
+fn rekeyed_problems(host: &str, config: &AgeConfig, file: &Path) -> (Vec<String>, bool) {
+    let host_pubkey = config.host_pubkey.as_deref().unwrap_or(DUMMY_PUBKEY).trim();
+    if host_pubkey == DUMMY_PUBKEY {
+        return (vec![], true);
+    }
+    if !file.exists() {
+        return (vec![format!("not rekeyed for {host}")], false);
+    }
+    let problems = match read_stanzas(file) {
+        Ok(stanzas) => recipient_problems(&[(host.to_string(), host_pubkey.to_string())], &stanzas),
+        Err(e) => vec![e.to_string()],
+    };
+    let problems = problems
+        .into_iter()
+        .map(|x| format!("copy for {host} {x}"))
+        .collect();
+    (problems, false)
+}
+
+/// List every secret with the hosts using it and the keys it is encrypted for, and flag those
+/// encrypted for the wrong recipients, for the dummy key, or used by no host.
+pub(crate) fn agenix_list() -> Result<()> {
+    let raw = read_from_repl(
+        "nixosConfigurations",
+        vec!["--json", "--apply", AGE_CONFIG_NIX],
+    )?;
+    let configs: BTreeMap<String, AgeConfig> = serde_json::from_str(&raw)?;
+
+    // Master keys are meant to be the same for all hosts; collect them all regardless
+    let mut master_keys: Vec<(String, String)> = vec![];
+    let mut unknown_masters = BTreeSet::new();
+    for config in configs.values() {
+        for identity in &config.master_identities {
+            let label = repo_path(&identity.identity).to_string_lossy().to_string();
+            match master_pubkey(identity) {
+                Some(pubkey) => master_keys.push((label, pubkey)),
+                None => {
+                    unknown_masters.insert(label);
+                }
+            }
+        }
+        for pubkey in &config.extra_encryption_pubkeys {
+            master_keys.push((pubkey.trim().to_string(), pubkey.trim().to_string()));
+        }
+    }
+    master_keys.sort_by(|a, b| a.1.cmp(&b.1));
+    master_keys.dedup_by(|a, b| a.1 == b.1);
+    for label in &unknown_masters {
+        log::warn!(
+            "Could not determine the public key of the master identity {label}, set its `pubkey`. Master keys are not checked."
+        );
+    }
+
+    // Hosts using each secret, with the path of their rekeyed copy in local storage
+    let mut users: BTreeMap<PathBuf, Vec<(&str, Option<PathBuf>)>> = BTreeMap::new();
+    let mut plain_users: BTreeMap<PathBuf, Vec<&str>> = BTreeMap::new();
+    let mut rekeyed = BTreeSet::new();
+    for (host, config) in &configs {
+        for secret in config.secrets.values() {
+            let file = secret.file.as_deref().map(repo_path);
+            match secret.rekey_file {
+                Some(ref rekey_file) => {
+                    let file = file.filter(|_| config.local_storage_dir.is_some());
+                    rekeyed.extend(file.clone());
+                    users
+                        .entry(repo_path(rekey_file))
+                        .or_default()
+                        .push((host, file));
+                }
+                None => {
+                    if let Some(file) = file {
+                        plain_users.entry(file).or_default().push(host);
+                    }
+                }
+            }
+        }
+    }
+    let storage_dirs: Vec<PathBuf> = configs
+        .values()
+        .filter_map(|x| x.local_storage_dir.as_deref().map(repo_path))
+        .collect();
+
+    let mut files: BTreeSet<PathBuf> = secret_files().into_iter().map(PathBuf::from).collect();
+    files.extend(users.keys().cloned());
+    files.extend(plain_users.keys().cloned());
+    let (mut stale, mut dummy, mut unreferenced) = (0, 0, 0);
+    for file in files.iter().filter(|x| !rekeyed.contains(*x)) {
+        log::info!("{}", file.to_string_lossy());
+        if let Some(hosts) = plain_users.get(file) {
+            log::info!("  hosts: {}", hosts.join(", "));
+            log::info!("  not managed by agenix-rekey, recipients are not checked");
+            continue;
+        }
+        let Some(hosts) = users.get(file) else {
+            let left_over = storage_dirs.iter().any(|x| file.starts_with(x));
+            log::warn!(
+                "  unreferenced: no host uses this secret{}",
+                if left_over {
+                    ", it is left over from an earlier rekey"
+                } else {
+                    ""
+                }
+            );
+            unreferenced += 1;
+            continue;
+        };
+
+        let mut host_names: Vec<&str> = hosts.iter().map(|(host, _)| *host).collect();
+        host_names.dedup();
+        log::info!("  hosts: {}", host_names.join(", "));
+
+        let mut problems = vec![];
+        let mut dummy_hosts = vec![];
+        if !file.exists() {
+            problems.push("does not exist".to_string());
+        } else {
+            let labels: Vec<&str> = master_keys
+                .iter()
+                .map(|(label, _)| label.as_str())
+                .collect();
+            log::info!("  master keys: {}", labels.join(", "));
+            if unknown_masters.is_empty() {
+                match read_stanzas(file) {
+                    Ok(stanzas) => problems.extend(recipient_problems(&master_keys, &stanzas)),
+                    Err(e) => problems.push(e.to_string()),
+                }
+            }
+            for (host, rekeyed_file) in hosts {
+                let Some(rekeyed_file) = rekeyed_file else {
+                    continue;
+                };
+                let (host_problems, is_dummy) =
+                    rekeyed_problems(host, &configs[*host], rekeyed_file);
+                problems.extend(host_problems);
+                if is_dummy {
+                    dummy_hosts.push(*host);
+                }
+            }
+        }
+
+        for problem in &problems {
+            log::warn!("  stale: {problem}");
+        }
+        if !dummy_hosts.is_empty() {
+            log::warn!(
+                "  dummy: encrypted for the dummy key instead of {}, which have no host key",
+                dummy_hosts.join(", ")
+            );
+            dummy += 1;
+        }
+        if !problems.is_empty() {
+            stale += 1;
+        }
+    }
+
+    log::info!(
+        "{} secrets, {stale} stale, {dummy} encrypted for dummy keys, {unreferenced} unreferenced.",
+        files.len() - rekeyed.intersection(&files).count()
+    );
+    if stale > 0 {
+        log::info!(
+            "Run `snow agenix update-masterkeys` if the master keys changed, and `snow agenix rekey` for the hosts."
+        );
+    }
+    Ok(())
+}
 
 pub(crate) fn agenix_update_masterkeys() -> Result<()> {
     let command = SnowCommand::new_agenix(
@@ -1,29 +1,84 @@
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
 
 use inquire::Confirm;
 use users::get_current_username;
 
 use crate::SnowError;
-use crate::options::RebuildMode;
+use crate::options::{HardwareReport, RebuildMode};
 use crate::util::Result;
 
 use super::runners::SnowCommand;
-use super::{agenix_rekey, fmt, git_add, rebuild};
+use super::util::{
+    HostProbe, SnowConfig, WaitOptions, WaitStage, check_rekey_host_key, extend_nix_sshopts,
+    generate_host_key, host_key_path, host_key_state_path, read_from_repl, restore_nix_sshopts,
+    split_destination, verify_deployment, wrap, write_host_key,
+};
+use super::{agenix_rekey, fmt, git_add, known_hosts, rebuild, scaffold_host};
 
 // Minimal config that enables SSH, flakes, and trusted-users on a stock NixOS installation,
 // importing the existing configuration so nothing else changes. Uses `nixos-rebuild test` so
-// the change is live immediately but not written to the boot entry.
+// the change is live immediately but not written to the boot entry. `@SSH@` is replaced by the
+// way logins are authenticated.
 const PREPARE_NIX: &str = r#"{ config, pkgs, lib, ... }: {
   imports = [ /etc/nixos/configuration.nix ];
   services.openssh.enable = true;
-  services.openssh.settings.PasswordAuthentication = true;
+@SSH@
   nix.settings.trusted-users = [ "@wheel" ];
   nix.settings.experimental-features = [ "nix-command" "flakes" ];
 }"#;
 
-pub(crate) fn assimilate_prepare() -> Result<()> {
+/// The prepare config, accepting passwords unless an authorized key for `username` is given.
+fn prepare_nix(authorized_key: Option<&str>, username: &str) -> String {
+    let ssh = match authorized_key {
+        None => "  services.openssh.settings.PasswordAuthentication = true;".to_string(),
+        Some(key) => {
+            let key = key
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace("${", "\\${");
+            format!(
+                "  services.openssh.settings.PasswordAuthentication = false;\n  services.openssh.settings.KbdInteractiveAuthentication = false;\n  users.users.{username}.openssh.authorizedKeys.keys = [ \"{key}\" ];"
+            )
+        }
+    };
+    PREPARE_NIX.replace("@SSH@", &ssh)
+}
+
+/// Read a public key given either literally or as the path of a file containing it.
+fn read_authorized_key(key_or_file: &str) -> Result<String> {
+    let key = match fs::read_to_string(key_or_file) {
+        Ok(content) => content
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        Err(_) => key_or_file.trim().to_string(),
+    };
+    match key.split_whitespace().collect::<Vec<_>>()[..] {
+        [key_type, _, ..]
+            if key_type.starts_with("ssh-")
+                || key_type.starts_with("ecdsa-")
+                || key_type.starts_with("sk-") =>
+        {
+            Ok(key)
+        }
+        _ => Err(SnowError::Env(format!(
+            "\"{key_or_file}\" is neither an SSH public key nor a file containing one"
+        ))),
+    }
+}
+
+pub(crate) fn assimilate_prepare(authorized_key: Option<&str>) -> Result<()> {
+    let authorized_key = authorized_key.map(read_authorized_key).transpose()?;
+    let username_os = get_current_username().unwrap_or_default();
+    let username = username_os.to_str().unwrap_or("user");
+
     let tmp = "/tmp/snow-assimilate-prepare.nix";
-    fs::write(tmp, PREPARE_NIX).map_err(SnowError::IO)?;
+    fs::write(tmp, prepare_nix(authorized_key.as_deref(), username)).map_err(SnowError::IO)?;
 
     let result = SnowCommand::new_nix(
         "nixos-rebuild".to_string(),
@@ -35,14 +90,14 @@ pub(crate) fn assimilate_prepare() -> Result<()> {
     let _ = fs::remove_file(tmp);
     result?;
 
-    let username_os = get_current_username().unwrap_or_default();
-    let username = username_os.to_str().unwrap_or("user");
-
     let ips = SnowCommand::new("hostname".to_string(), vec!["-I"], false)
         .run_with_return()
         .unwrap_or_default();
 
-    log::info!("SSH is now enabled with password authentication.");
+    match authorized_key {
+        Some(_) => log::info!("SSH is now enabled for the given key, passwords are not accepted."),
+        None => log::info!("SSH is now enabled with password authentication."),
+    }
     log::info!("From the managing host, run:");
     for ip in ips.split_whitespace() {
         log::info!("  snow assimilate {}@{} <nixos-config>", username, ip);
@@ -51,51 +106,300 @@ pub(crate) fn assimilate_prepare() -> Result<()> {
     Ok(())
 }
 
-pub(crate) fn assimilate_run(target: &str, nixos_configuration: &str) -> Result<()> {
-    // 1. Copy our SSH public key so all subsequent steps authenticate without a password
-    log::info!("Copying SSH public key to {}...", target);
+/// The snow config of the host, reaching it at `target` rather than its configured target host.
+/// Its ssh settings (`sshUser`, `sshIdentityFile`, `sshJumpHost`, ...) still apply.
+fn target_snow_config(target: &str, nixos_configuration: &str) -> Result<SnowConfig> {
+    let mut snow_config = SnowConfig::get_snow_config(nixos_configuration)?;
+    snow_config.target_host = Some(target.to_string());
+    Ok(snow_config)
+}
+
+/// `ssh` running `remote_command` on the target of `snow_config`.
+fn ssh(snow_config: &SnowConfig, remote_command: &[&str]) -> SnowCommand {
+    let args = snow_config.ssh_command(remote_command).unwrap_or_default();
     SnowCommand::new(
-        "ssh-copy-id".to_string(),
-        vec!["-o", "StrictHostKeyChecking=accept-new", target],
+        "ssh".to_string(),
+        args.iter().map(|x| x.as_str()).collect(),
         false,
     )
-    .run_verbose()?;
+}
 
-    // 2. Fetch the target's host public key and store it for agenix
-    log::info!("Fetching host public key from {}...", target);
-    let pubkey = SnowCommand::new(
-        "ssh".to_string(),
-        vec![target, "cat", "/etc/ssh/ssh_host_ed25519_key.pub"],
+/// `ssh-copy-id` to the target of `snow_config`, accepting its host key on first contact.
+fn ssh_copy_id(snow_config: &SnowConfig) -> SnowCommand {
+    let mut args = vec![
+        "-o".to_string(),
+        "StrictHostKeyChecking=accept-new".to_string(),
+    ];
+    args.extend(snow_config.ssh_option_args());
+    args.extend(snow_config.ssh_destination());
+    SnowCommand::new(
+        "ssh-copy-id".to_string(),
+        args.iter().map(|x| x.as_str()).collect(),
         false,
     )
+}
+
+/// Quote an argument for `sh`.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Authentication methods the ssh server of `target` offers, from the "Permission denied"
+/// message printed when trying none of them.
+fn parse_auth_methods(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .find_map(|line| line.split_once("Permission denied (")?.1.split_once(')'))
+        .map(|(methods, _)| methods.split(',').map(|x| x.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether the target accepts logins with a password, directly or through keyboard-interactive.
+fn accepts_passwords(snow_config: &SnowConfig) -> Result<bool> {
+    let ssh_command = snow_config
+        .ssh_command(&["true"])
+        .unwrap_or_default()
+        .iter()
+        .map(|x| shell_quote(x))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let probe = format!(
+        "ssh -o BatchMode=yes -o ConnectTimeout=5 -o StrictHostKeyChecking=accept-new -o PubkeyAuthentication=no -o PreferredAuthentications=none {ssh_command} 2>&1"
+    );
+    let (_, output) =
+        SnowCommand::new("sh".to_string(), vec!["-c", &probe], false).run_with_status()?;
+    Ok(parse_auth_methods(&output)
+        .iter()
+        .any(|method| method == "password" || method == "keyboard-interactive"))
+}
+
+// Summarizes the settings of a NixOS config that get lost most easily when replacing it. Applied
+// to the config of the machine and to the nixosConfiguration replacing it. Passed through a
+// remote shell in single quotes, so it must not contain any.
+const SUMMARY_NIX: &str = r#"config: with builtins; let
+  enabled = v: let r = tryEval (isAttrs v && (v.enable or false) == true); in r.success && r.value;
+in {
+  users = filter (n: config.users.users.${n}.isNormalUser) (attrNames config.users.users);
+  "file systems" = attrNames config.fileSystems;
+  "boot loaders" = filter (n: enabled config.boot.loader.${n}) [ "systemd-boot" "grub" "generic-extlinux-compatible" ];
+  "network interfaces" = attrNames config.networking.interfaces;
+  services = filter (n: enabled config.services.${n}) (attrNames config.services);
+}"#;
+
+type Summary = BTreeMap<String, Vec<String>>;
+
+/// Settings of the original summary missing from the new one, by category.
+fn dropped_settings(original: &Summary, new: &Summary) -> Vec<(String, Vec<String>)> {
+    original
+        .iter()
+        .map(|(category, values)| {
+            let kept = new.get(category).cloned().unwrap_or_default();
+            let dropped: Vec<String> = values
+                .iter()
+                .filter(|value| !kept.contains(value))
+                .cloned()
+                .collect();
+            (category.clone(), dropped)
+        })
+        .filter(|(_, dropped)| !dropped.is_empty())
+        .collect()
+}
+
+/// Copy `/etc/nixos` of the target into `hosts/<name>/original/`, replacing an earlier copy.
+/// The copy may hold secrets and is only meant for review, so it is kept out of git (and thereby
+/// the flake and formatting) by a `.gitignore` of its own. Best-effort: failing to read some of
+/// it only warns.
+fn fetch_original_config(snow_config: &SnowConfig, nixos_configuration: &str) -> Result<()> {
+    let target = snow_config.ssh_destination().unwrap_or_default();
+    let original = format!("hosts/{nixos_configuration}/original");
+    if let Err(e) = fs::remove_dir_all(&original)
+        && e.kind() != ErrorKind::NotFound
+    {
+        return Err(e.into());
+    }
+    fs::create_dir_all(&original)?;
+    fs::write(format!("{original}/.gitignore"), "*\n")?;
+
+    log::info!("Saving the configuration of {target} to {original}...");
+    let archive = match snow_config.use_remote_sudo {
+        true => "sudo -n tar -C /etc -cf - nixos",
+        false => "tar -C /etc -cf - nixos",
+    };
+    let ssh_command = snow_config
+        .ssh_command(&[archive])
+        .unwrap_or_default()
+        .iter()
+        .map(|x| shell_quote(x))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let copy = format!(
+        "ssh {ssh_command} | tar -xf - -C {} --strip-components=1",
+        shell_quote(&original)
+    );
+    match SnowCommand::new("sh".to_string(), vec!["-c", &copy], false).run_with_status() {
+        Ok((true, _)) => log::info!("Review {original} for settings to carry over."),
+        _ => log::warn!(
+            "Could not copy all of /etc/nixos from {target}, {original} may be incomplete"
+        ),
+    }
+    log::info!(
+        "{original} is not committed, as it may hold secrets. Remove its .gitignore to commit it anyway."
+    );
+    Ok(())
+}
+
+/// Report which notable settings of the target's current config the nixosConfiguration drops.
+/// Returns whether anything is dropped.
+fn report_dropped_settings(
+    snow_config: &SnowConfig,
+    target: &str,
+    nixos_configuration: &str,
+) -> Result<bool> {
+    let remote_eval = format!(
+        "nix-instantiate --eval --strict --json --expr '({SUMMARY_NIX}) (import <nixpkgs/nixos> {{}}).config'"
+    );
+    // The report is only informational, so failing to put it together does not stop the run
+    let original = match ssh(snow_config, &[&remote_eval]).run_with_status() {
+        Ok((true, output)) => serde_json::from_str::<Summary>(&output).ok(),
+        _ => None,
+    };
+    let Some(original) = original else {
+        log::warn!("Could not evaluate the current configuration of {target}, skipping the report");
+        return Ok(false);
+    };
+    let new = read_from_repl(
+        &format!("nixosConfigurations.{nixos_configuration}.config"),
+        vec!["--json", "--apply", SUMMARY_NIX],
+    )
+    .ok()
+    .and_then(|output| serde_json::from_str::<Summary>(&output).ok());
+    let Some(new) = new else {
+        log::warn!(
+            "Could not evaluate the configuration of {nixos_configuration}, skipping the report"
+        );
+        return Ok(false);
+    };
+
+    let dropped = dropped_settings(&original, &new);
+    if dropped.is_empty() {
+        log::info!(
+            "{nixos_configuration} keeps the users, file systems, boot loader, network interfaces and services of {target}."
+        );
+        return Ok(false);
+    }
+    log::warn!("{nixos_configuration} drops these settings of {target}:");
+    for (category, values) in &dropped {
+        log::warn!("  {category}: {}", values.join(", "));
+    }
+    Ok(true)
+}
+
+/// Run `nixos-facter` on the target and store its report as `hosts/<name>/facter.json`.
+fn write_facter_report(
+    snow_config: &SnowConfig,
+    target: &str,
+    nixos_configuration: &str,
+) -> Result<()> {
+    log::info!("Generating hardware report on {}...", target);
+    let report = ssh(
+        snow_config,
+        &["sudo nix --extra-experimental-features 'nix-command flakes' run nixpkgs#nixos-facter"],
+    )
     .run_with_return()?;
+    // Fail on anything but a report, rather than committing it
+    serde_json::from_str::<serde_json::Value>(&report)?;
+
+    let report_path = format!("hosts/{}/facter.json", nixos_configuration);
+    fs::write(&report_path, &report).map_err(SnowError::IO)?;
+    log::info!("Wrote hardware report to {}", report_path);
+
+    // The flake only sees the report once it is staged
+    git_add(false)?;
+    let wired = read_from_repl(
+        &format!("nixosConfigurations.{nixos_configuration}.config.snow.facterReport"),
+        vec!["--json"],
+    )
+    .is_ok_and(|report| report.trim() != "null");
+    if !wired {
+        log::warn!(
+            "Set `snow.facterReport = ./facter.json;` for {nixos_configuration} to use the report"
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn assimilate_run(
+    target: &str,
+    nixos_configuration: &str,
+    create: bool,
+    hardware_report: HardwareReport,
+    wait_timeout: u64,
+    wait_for_system: bool,
+) -> Result<()> {
+    // 0. Scaffold the host in the flake if asked to
+    if create {
+        scaffold_host(nixos_configuration, target)?;
+    }
+
+    let snow_config = target_snow_config(target, nixos_configuration)?;
 
-    let pubkey_path = format!("hosts/{}/ssh_host_ed25519_key.pub", nixos_configuration);
-    fs::write(&pubkey_path, pubkey.trim()).map_err(SnowError::IO)?;
-    log::info!("Wrote host pubkey to {}", pubkey_path);
+    // Remember whether passwords were accepted, to make sure they no longer are in the end
+    let accepted_passwords = accepts_passwords(&snow_config)?;
+
+    // 1. Copy our SSH public key so all subsequent steps authenticate without a password
+    log::info!("Copying SSH public key to {}...", target);
+    ssh_copy_id(&snow_config).run_verbose()?;
+
+    // 2. Fetch the target's host public key and store it for agenix
+    log::info!("Fetching host public key from {}...", target);
+    let pubkey =
+        ssh(&snow_config, &["cat", "/etc/ssh/ssh_host_ed25519_key.pub"]).run_with_return()?;
+
+    let pubkey_path = host_key_path(nixos_configuration)?;
+    write_host_key(&pubkey_path, &pubkey)?;
+    git_add(false)?;
+    check_rekey_host_key(nixos_configuration, &pubkey_path)?;
 
     // 3. Rekey agenix secrets to include the new host
     log::info!("Rekeying secrets for new host...");
     agenix_rekey(false, false)?;
 
-    // 4. Generate and save hardware configuration
-    log::info!("Generating hardware configuration on {}...", target);
-    let hw_config = SnowCommand::new(
-        "ssh".to_string(),
-        vec![target, "nixos-generate-config", "--show-hardware-config"],
-        false,
-    )
-    .run_with_return()?;
+    // 4. Generate and save hardware configuration and/or report
+    if hardware_report != HardwareReport::Facter {
+        log::info!("Generating hardware configuration on {}...", target);
+        let hw_config = ssh(
+            &snow_config,
+            &["nixos-generate-config", "--show-hardware-config"],
+        )
+        .run_with_return()?;
+
+        let hw_path = format!("hosts/{}/hardware-configuration.nix", nixos_configuration);
+        fs::write(&hw_path, &hw_config).map_err(SnowError::IO)?;
+        log::info!("Wrote hardware configuration to {}", hw_path);
+    }
+    if hardware_report != HardwareReport::GenerateConfig {
+        write_facter_report(&snow_config, target, nixos_configuration)?;
+    }
 
-    let hw_path = format!("hosts/{}/hardware-configuration.nix", nixos_configuration);
-    fs::write(&hw_path, &hw_config).map_err(SnowError::IO)?;
-    log::info!("Wrote hardware configuration to {}", hw_path);
+    // 5. Keep the original configuration around for reference
+    fetch_original_config(&snow_config, nixos_configuration)?;
 
-    // 5. Format, then stage everything (pubkey, rekeyed secrets, hardware config)
+    // 6. Format, then stage everything (pubkey, rekeyed secrets and hardware config)
     fmt()?;
     git_add(false)?;
 
-    // 6. Build and activate on next boot — avoids switching mid-session on the target
+    // 7. Show what gets lost, and let the user decide whether to go on
+    let drops_settings = report_dropped_settings(&snow_config, target, nixos_configuration)?;
+    let do_deploy = Confirm::new(&format!("Deploy {nixos_configuration} to {target}?"))
+        .with_default(!drops_settings)
+        .prompt()
+        .map_err(|_| SnowError::Env("prompt cancelled".to_string()))?;
+    if !do_deploy {
+        log::info!("Not deploying. Run this again once {nixos_configuration} is ready.");
+        return Ok(());
+    }
+
+    // 8. Build and activate on next boot — avoids switching mid-session on the target
     log::info!(
         "Building and deploying {} to {} (boot mode)...",
         nixos_configuration,
@@ -112,15 +416,283 @@ pub(crate) fn assimilate_run(target: &str, nixos_configuration: &str) -> Result<
         &None,
     )?;
 
-    // 7. Confirm and reboot
+    // 9. Confirm and reboot
     let do_reboot = Confirm::new(&format!("Deployment complete. Reboot {} now?", target))
         .with_default(true)
         .prompt()
         .map_err(|_| SnowError::Env("prompt cancelled".to_string()))?;
 
     if do_reboot {
-        SnowCommand::new("ssh".to_string(), vec![target, "sudo", "reboot"], false).run_verbose()?;
+        let probe = HostProbe {
+            name: target,
+            ssh_args: snow_config.ssh_command(&[]).unwrap_or_default(),
+            options: WaitOptions {
+                timeout: Duration::from_secs(wait_timeout),
+                system_running: wait_for_system,
+            },
+        };
+        let boot_id = probe.boot_id();
+        ssh(&snow_config, &["sudo", "reboot"]).run_verbose()?;
+        log::info!("Waiting for {} to come back up...", target);
+        probe.wait(probe.options.ready_stage(), boot_id.as_deref())?;
+        verify_deployment(&probe, nixos_configuration)?;
+    }
+
+    // 10. Passwords only had to be accepted until our key was in place
+    if accepted_passwords {
+        if !do_reboot {
+            log::warn!(
+                "{target} accepts passwords until it is rebooted into {nixos_configuration}"
+            );
+        } else if accepts_passwords(&snow_config)? {
+            return Err(SnowError::Env(format!(
+                "{target} still accepts passwords, disable services.openssh.settings.PasswordAuthentication and KbdInteractiveAuthentication in {nixos_configuration}"
+            )));
+        } else {
+            log::info!("{target} no longer accepts passwords.");
+        }
     }
 
     Ok(())
 }
+
+/// The installer's host key changes with every boot and is of no interest, so it is neither
+/// checked nor remembered.
+const INSTALLER_SSH_OPTIONS: [&str; 4] = [
+    "-o",
+    "StrictHostKeyChecking=no",
+    "-o",
+    "UserKnownHostsFile=/dev/null",
+];
+
+/// NixOS installer kexec'd into if no other image is given, see nix-community/nixos-images.
+fn default_kexec_url(arch: &str) -> String {
+    format!(
+        "https://github.com/nix-community/nixos-images/releases/latest/download/nixos-kexec-installer-noninteractive-{arch}-linux.tar.gz"
+    )
+}
+
+/// Shell command downloading and running the kexec installer, as root.
+fn kexec_command(url: &str, as_root: bool) -> String {
+    let script = format!("curl -fsSL {url} | tar -xzf - -C /root && /root/kexec/run");
+    match as_root {
+        true => format!("sh -c '{script}'"),
+        false => format!("sudo sh -c '{script}'"),
+    }
+}
+
+/// Build an attribute of the nixosConfiguration, returning its store path.
+fn build_attribute(nixos_configuration: &str, attribute: &str) -> Result<String> {
+    let attr = format!("nixosConfigurations.{nixos_configuration}.config.system.build.{attribute}");
+    SnowCommand::new_nix(
+        "nix".to_string(),
+        vec!["build", "--no-link", &wrap(&attr, true)],
+        false,
+    )
+    .run_progress(format!("{nixos_configuration} ({attribute})"))?;
+    Ok(read_from_repl(&format!("{attr}.outPath"), vec!["--raw"])?
+        .trim()
+        .to_string())
+}
+
+/// Install the nixosConfiguration on any Linux machine reachable over ssh: kexec into a NixOS
+/// installer, partition the disks with the host's disko config, place a freshly generated host
+/// key, and run `nixos-install`.
+pub(crate) fn assimilate_install(
+    target: &str,
+    nixos_configuration: &str,
+    create: bool,
+    kexec_url: Option<&str>,
+    wait_timeout: u64,
+    wait_for_system: bool,
+) -> Result<()> {
+    if create {
+        scaffold_host(nixos_configuration, target)?;
+    }
+    let snow_config = target_snow_config(target, nixos_configuration)?;
+    let destination = snow_config.ssh_destination().unwrap_or_default();
+    let (user, host) = split_destination(&destination);
+    let installer = format!("root@{host}");
+    let options = WaitOptions {
+        timeout: Duration::from_secs(wait_timeout),
+        system_running: wait_for_system,
+    };
+    // The installer is reached like the target, but as root and trusting any host key
+    let mut installer_options: Vec<String> = INSTALLER_SSH_OPTIONS.map(String::from).to_vec();
+    installer_options.extend(snow_config.ssh_option_args());
+    let mut installer_args = installer_options.clone();
+    installer_args.push(installer.clone());
+    let installer_probe = HostProbe {
+        name: host,
+        ssh_args: installer_args.clone(),
+        options,
+    };
+    let on_installer = |command: &str| {
+        let mut args: Vec<&str> = installer_args.iter().map(|x| x.as_str()).collect();
+        args.push(command);
+        SnowCommand::new("ssh".to_string(), args, false)
+    };
+
+    // 1. Copy our SSH public key, the kexec installer takes over root's authorized keys
+    log::info!("Copying SSH public key to {}...", target);
+    ssh_copy_id(&snow_config).run_verbose()?;
+
+    // 2. Boot into the NixOS installer
+    let kexec_url = match kexec_url {
+        Some(url) => url.to_string(),
+        None => default_kexec_url(
+            ssh(&snow_config, &["uname", "-m"])
+                .run_with_return()?
+                .trim(),
+        ),
+    };
+    let previous_boot = HostProbe {
+        name: host,
+        ssh_args: snow_config.ssh_command(&[]).unwrap_or_default(),
+        options,
+    }
+    .boot_id();
+    log::info!("Booting {host} into the NixOS installer...");
+    ssh(
+        &snow_config,
+        &[&kexec_command(&kexec_url, user == Some("root"))],
+    )
+    .run_verbose()?;
+    installer_probe.wait(WaitStage::Login, previous_boot.as_deref())?;
+
+    // 3. Generate the host key, and rekey secrets for it
+    log::info!("Generating the host key of {nixos_configuration}...");
+    let key = host_key_state_path(nixos_configuration)?;
+    generate_host_key(&key, nixos_configuration)?;
+    let pubkey_path = host_key_path(nixos_configuration)?;
+    write_host_key(
+        &pubkey_path,
+        &fs::read_to_string(format!("{}.pub", key.display()))?,
+    )?;
+    git_add(false)?;
+    check_rekey_host_key(nixos_configuration, &pubkey_path)?;
+    known_hosts(&None)?;
+    agenix_rekey(false, false)?;
+    git_add(false)?;
+
+    // 4. Partition and mount the disks
+    let disko_script = build_attribute(nixos_configuration, "diskoScript")?;
+    let previous_sshopts = extend_nix_sshopts(&installer_options)?;
+    let result = (|| {
+        SnowCommand::new_nix(
+            "nix".to_string(),
+            vec!["copy", "--to", &format!("ssh://{installer}"), &disko_script],
+            false,
+        )
+        .run_verbose()?;
+        log::info!("Partitioning the disks of {host}...");
+        on_installer(&disko_script).run_verbose()?;
+
+        // 5. Place the host key where the installed system expects it
+        on_installer("install -d -m 0755 /mnt/etc/ssh").run_silent()?;
+        let mut scp_args: Vec<&str> = installer_options.iter().map(|x| x.as_str()).collect();
+        let key_path = key.to_string_lossy();
+        let remote_key = format!("{installer}:/mnt/etc/ssh/ssh_host_ed25519_key");
+        scp_args.extend([key_path.as_ref(), remote_key.as_str()]);
+        SnowCommand::new("scp".to_string(), scp_args, false).run_silent()?;
+
+        // 6. Copy the system and install it
+        let toplevel = build_attribute(nixos_configuration, "toplevel")?;
+        log::info!("Installing {nixos_configuration} on {host}...");
+        SnowCommand::new_nix(
+            "nix".to_string(),
+            vec![
+                "copy",
+                "--to",
+                &format!("ssh://{installer}?remote-store=local?root=/mnt"),
+                &toplevel,
+            ],
+            false,
+        )
+        .run_verbose()?;
+        on_installer(&format!(
+            "nixos-install --root /mnt --no-root-passwd --no-channel-copy --system {toplevel}"
+        ))
+        .run_verbose()
+    })();
+    restore_nix_sshopts(previous_sshopts);
+    result?;
+    fs::remove_file(&key)?;
+    fs::remove_file(format!("{}.pub", key.display()))?;
+
+    // 7. Boot into the installed system
+    log::info!("Rebooting {host} into {nixos_configuration}...");
+    let installer_boot = installer_probe.boot_id();
+    on_installer("reboot").run_silent()?;
+    // The installed system has the recorded key. It is looked up by name, as the target given
+    // may not be the address the flake knows the host by.
+    let host_key_alias = format!("HostKeyAlias={nixos_configuration}");
+    let mut ssh_args = vec!["-o".to_string(), host_key_alias];
+    ssh_args.extend(snow_config.ssh_command_for(nixos_configuration, &[]));
+    let probe = HostProbe {
+        name: host,
+        ssh_args,
+        options,
+    };
+    probe.wait(options.ready_stage(), installer_boot.as_deref())?;
+    verify_deployment(&probe, nixos_configuration)?;
+    log::info!("Done!");
+
+    Ok(())
+}
+
+#[test]
+fn test_kexec_command() {
+    assert_eq!(
+        kexec_command("https://example.org/kexec.tar.gz", false),
+        "sudo sh -c 'curl -fsSL https://example.org/kexec.tar.gz | tar -xzf - -C /root && /root/kexec/run'"
+    );
+    assert_eq!(
+        default_kexec_url("aarch64"),
+        "https://github.com/nix-community/nixos-images/releases/latest/download/nixos-kexec-installer-noninteractive-aarch64-linux.tar.gz"
+    );
+}
+
+#[test]
+fn test_prepare_nix() {
+    assert!(
+        prepare_nix(None, "nixos")
+            .contains("services.openssh.settings.PasswordAuthentication = true;")
+    );
+    let config = prepare_nix(Some("ssh-ed25519 AAAAC3 me@laptop"), "nixos");
+    assert!(config.contains("services.openssh.settings.PasswordAuthentication = false;"));
+    assert!(config.contains(
+        "users.users.nixos.openssh.authorizedKeys.keys = [ \"ssh-ed25519 AAAAC3 me@laptop\" ];"
+    ));
+
+    assert!(read_authorized_key("ssh-ed25519 AAAAC3").is_ok());
+    assert!(read_authorized_key("/nonexistent/id_ed25519.pub").is_err());
+    assert_eq!(
+        parse_auth_methods("admin@10.0.0.5: Permission denied (publickey,password).\n"),
+        vec!["publickey", "password"]
+    );
+}
+
+#[test]
+fn test_dropped_settings() {
+    let summary = |users: &[&str], services: &[&str]| {
+        Summary::from([
+            (
+                "users".to_string(),
+                users.iter().map(|x| x.to_string()).collect(),
+            ),
+            (
+                "services".to_string(),
+                services.iter().map(|x| x.to_string()).collect(),
+            ),
+        ])
+    };
+    assert_eq!(
+        dropped_settings(
+            &summary(&["alice", "bob"], &["openssh", "nginx"]),
+            &summary(&["alice"], &["openssh", "nginx", "fail2ban"])
+        ),
+        vec![("users".to_string(), vec!["bob".to_string()])]
+    );
+    assert!(!SUMMARY_NIX.contains('\''));
+}
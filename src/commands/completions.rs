@@ -0,0 +1,59 @@
+use clap_complete::CompletionCandidate;
+use clap_complete::env::{Bash, EnvCompleter, Fish, Zsh};
+
+use crate::options::CompletionShell;
+use crate::util::Result;
+
+use super::util::{
+    dev_shell_names, home_configuration_names, host_tags, nixos_configuration_names, secret_files,
+};
+
+fn to_candidates(values: Vec<String>) -> Vec<CompletionCandidate> {
+    values.into_iter().map(CompletionCandidate::new).collect()
+}
+
+pub(crate) fn complete_hosts() -> Vec<CompletionCandidate> {
+    to_candidates(nixos_configuration_names(true).unwrap_or_default())
+}
+
+pub(crate) fn complete_tags() -> Vec<CompletionCandidate> {
+    let mut tags: Vec<String> = host_tags(true)
+        .unwrap_or_default()
+        .into_values()
+        .flatten()
+        .map(|tag| format!("@{tag}"))
+        .collect();
+    tags.sort();
+    tags.dedup();
+    to_candidates(tags)
+}
+
+pub(crate) fn complete_hosts_and_tags() -> Vec<CompletionCandidate> {
+    let mut candidates = complete_hosts();
+    candidates.extend(complete_tags());
+    candidates
+}
+
+pub(crate) fn complete_dev_shells() -> Vec<CompletionCandidate> {
+    to_candidates(dev_shell_names(true).unwrap_or_default())
+}
+
+pub(crate) fn complete_home_configurations() -> Vec<CompletionCandidate> {
+    to_candidates(home_configuration_names(true).unwrap_or_default())
+}
+
+pub(crate) fn complete_secrets() -> Vec<CompletionCandidate> {
+    to_candidates(secret_files())
+}
+
+/// Print the script registering snow's completions with the given shell. Completions are
+/// generated dynamically by snow itself, so the script only has to be sourced once.
+pub(crate) fn completions(shell: &CompletionShell) -> Result<()> {
+    let completer: &dyn EnvCompleter = match shell {
+        CompletionShell::Bash => &Bash,
+        CompletionShell::Zsh => &Zsh,
+        CompletionShell::Fish => &Fish,
+    };
+    completer.write_registration("COMPLETE", "snow", "snow", "snow", &mut std::io::stdout())?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use crate::{SnowError, util::Result};
+
+use super::runners::SnowCommand;
+use super::util::{CommandOutput, CustomCommand, FlakeConfig};
+
+fn list_custom_commands(commands: &std::collections::BTreeMap<String, CustomCommand>) {
+    if commands.is_empty() {
+        log::info!("The flake does not define any commands in snow.commands.");
+        return;
+    }
+    log::info!("Available commands:");
+    for (name, custom_command) in commands {
+        let sudo = match custom_command.sudo {
+            true => " (sudo)",
+            false => "",
+        };
+        log::info!(
+            "  {name}{sudo}: {}",
+            custom_command
+                .description
+                .as_deref()
+                .unwrap_or(&custom_command.command)
+        );
+    }
+}
+
+fn custom_command_to_snow_command(
+    name: &str,
+    custom_command: &CustomCommand,
+    args: &[String],
+) -> SnowCommand {
+    // Run through sh so that the command line may contain pipes, env vars etc. The recipe's own
+    // arguments come first, followed by whatever was passed on the command line.
+    let script = format!("{} \"$@\"", custom_command.command);
+    let mut sh_args = vec!["-c", &script, name];
+    sh_args.extend(custom_command.args.iter().map(|x| x.as_str()));
+    sh_args.extend(args.iter().map(|x| x.as_str()));
+    SnowCommand::new("sh".to_string(), sh_args, custom_command.sudo)
+}
+
+pub(crate) fn custom(name: &Option<String>, args: &[String]) -> Result<()> {
+    let flake_config = FlakeConfig::get_flake_config()?;
+    let Some(name) = name else {
+        list_custom_commands(&flake_config.commands);
+        return Ok(());
+    };
+    let Some(custom_command) = flake_config.commands.get(name) else {
+        list_custom_commands(&flake_config.commands);
+        return Err(SnowError::SnowConfig(format!(
+            "no command \"{name}\" defined in snow.commands"
+        )));
+    };
+
+    let command = custom_command_to_snow_command(name, custom_command, args);
+    match custom_command.output {
+        CommandOutput::Progress => command.run_progress(name.to_string()),
+        CommandOutput::Silent => command.run_silent(),
+        CommandOutput::Verbose => command.run_verbose(),
+    }
+}
+
+#[test]
+fn test_custom_command() {
+    let custom_command = CustomCommand {
+        command: "./scripts/deploy-docs.sh".to_string(),
+        description: None,
+        args: vec!["--fast".to_string()],
+        sudo: true,
+        output: CommandOutput::Silent,
+    };
+    assert_eq!(
+        custom_command_to_snow_command("docs", &custom_command, &["staging".to_string()])
+            .to_string(),
+        "sudo sh -c ./scripts/deploy-docs.sh \"$@\" docs --fast staging"
+    );
+}
@@ -0,0 +1,177 @@
+use inquire::Confirm;
+use serde::{Deserialize, Serialize};
+use std::{io::ErrorKind, path::PathBuf};
+
+use crate::{
+    Result, SnowError, agenix_rekey,
+    commands::{
+        runners::SnowCommand,
+        util::{
+            SnowConfig, hypervisor, nixos_configuration_names, read_from_repl, repo_relative,
+            split_destination, state_dir, vm_host_key_path,
+        },
+    },
+    git_add, known_hosts,
+};
+
+/// What rekeying without the host needs to know once the host is gone from the flake, recorded
+/// by the first run of `deprovision`.
+#[derive(Deserialize, Serialize, Default)]
+struct DeprovisionState {
+    rekeyed_secrets: Option<PathBuf>,
+}
+
+impl DeprovisionState {
+    fn path(vm_configuration: &str) -> Result<PathBuf> {
+        Ok(state_dir("deprovision")?.join(format!("{vm_configuration}.json")))
+    }
+
+    fn load(vm_configuration: &str) -> Result<Option<Self>> {
+        match std::fs::read_to_string(Self::path(vm_configuration)?) {
+            Ok(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, vm_configuration: &str) -> Result<()> {
+        std::fs::write(
+            Self::path(vm_configuration)?,
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    fn remove(vm_configuration: &str) -> Result<()> {
+        match std::fs::remove_file(Self::path(vm_configuration)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where agenix-rekey stores the secrets rekeyed for the host, if it uses local storage.
+fn rekeyed_secrets_dir(vm_configuration: &str) -> Option<PathBuf> {
+    let raw = read_from_repl(
+        &format!("nixosConfigurations.{vm_configuration}.config.age.rekey.localStorageDir"),
+        vec!["--json", "--apply", "toString"],
+    )
+    .ok()?;
+    repo_relative(&serde_json::from_str::<String>(&raw).ok()?)
+}
+
+/// Rekey secrets without the host. agenix-rekey rekeys for every nixosConfiguration, so the host
+/// has to be gone from the flake, which is why this is a separate run of `deprovision`.
+fn rekey_without(vm_configuration: &str, confirm: impl Fn(&str) -> bool) -> Result<()> {
+    if nixos_configuration_names(false)?.contains(&vm_configuration.to_string()) {
+        return Err(SnowError::Env(format!(
+            "{vm_configuration} is still a nixosConfiguration, remove it before rekeying secrets without it"
+        )));
+    }
+    let state = match DeprovisionState::load(vm_configuration)? {
+        Some(state) => state,
+        None => {
+            log::warn!(
+                "{vm_configuration} was not deprovisioned before, its rekeyed secrets are left in place"
+            );
+            DeprovisionState::default()
+        }
+    };
+    if !confirm(&format!("Rekey secrets without {vm_configuration}?")) {
+        return Ok(());
+    }
+    if let Some(ref rekeyed_secrets) = state.rekeyed_secrets
+        && rekeyed_secrets.exists()
+    {
+        std::fs::remove_dir_all(rekeyed_secrets)?;
+    }
+    git_add(false)?;
+    agenix_rekey(false, false)?;
+    git_add(false)?;
+    DeprovisionState::remove(vm_configuration)?;
+    log::info!("Done!");
+    Ok(())
+}
+
+/// Undo `provision`: destroy the VM and forget its host key. Once the host is removed from the
+/// flake, a second run with `rekey_only` rekeys secrets without it.
+pub(crate) fn deprovision(vm_configuration: &str, yes: bool, rekey_only: bool) -> Result<()> {
+    let confirm = |message: &str| {
+        yes || Confirm::new(message)
+            .with_default(false)
+            .prompt()
+            .is_ok_and(|x| x)
+    };
+    if rekey_only {
+        return rekey_without(vm_configuration, confirm);
+    }
+    let (snow_config, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    DeprovisionState {
+        rekeyed_secrets: rekeyed_secrets_dir(vm_configuration),
+    }
+    .save(vm_configuration)?;
+
+    // 1. Stop and destroy the VM
+    let hypervisor = hypervisor(vm_configuration, &vm_config)?;
+    match hypervisor.exists()? {
+        false => log::info!("Skipping the VM, it does not exist"),
+        true => {
+            let status = hypervisor.status()?;
+            if confirm(&format!(
+                "Destroy {} ({vm_configuration}) and all of its disks?",
+                hypervisor.vm_name()
+            )) {
+                if status.status != "stopped" {
+                    log::info!("Shutting down {vm_configuration}...");
+                    hypervisor.stop()?;
+                }
+                log::info!("Destroying {vm_configuration}...");
+                hypervisor.destroy()?;
+            }
+        }
+    }
+
+    // 2. Remove the host key
+    let host_key = vm_host_key_path(vm_configuration)?;
+    if host_key.exists()
+        && confirm(&format!(
+            "Remove the host key {}?",
+            host_key.to_string_lossy()
+        ))
+    {
+        std::fs::remove_file(&host_key)?;
+    }
+
+    // 3. Drop known_hosts entries
+    if confirm(&format!(
+        "Remove {vm_configuration} from the known_hosts files?"
+    )) {
+        known_hosts(&None)?;
+        let mut stale: Vec<String> = vm_config.ip.iter().cloned().collect();
+        if let Some(ref target_host) = snow_config.target_host {
+            let (_, target_host) = split_destination(target_host);
+            stale.push(match snow_config.target_port {
+                Some(port) if port != 22 => format!("[{target_host}]:{port}"),
+                _ => target_host.to_string(),
+            });
+        }
+        for entry in stale {
+            // Fails if there is no ~/.ssh/known_hosts, in which case there is nothing to remove
+            if let Err(e) =
+                SnowCommand::new("ssh-keygen".to_string(), vec!["-R", &entry], false).run_silent()
+            {
+                log::debug!("could not remove {entry} from known_hosts: {e}");
+            }
+        }
+    }
+
+    // 4. Stage everything
+    if confirm("Stage the changes with git?") {
+        git_add(false)?;
+    }
+    log::info!(
+        "Done! Remove {vm_configuration} from nixosConfigurations, then run `snow deprovision {vm_configuration} --rekey-only` to rekey secrets without it."
+    );
+
+    Ok(())
+}
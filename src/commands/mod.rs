@@ -4,26 +4,40 @@ mod agenix;
 mod assimilate;
 mod build;
 mod bump;
+mod completions;
+mod custom;
 mod debug;
+mod deprovision;
 mod eval;
 mod git;
 mod misc;
+mod new;
 mod provision;
 mod rebuild;
+mod remote;
 mod runners;
 mod shell;
+mod ssh_config;
 mod store;
 mod util;
+mod vm;
 
 pub(crate) use agenix::*;
 pub(crate) use assimilate::*;
 pub(crate) use build::*;
 pub(crate) use bump::*;
+pub(crate) use completions::*;
+pub(crate) use custom::*;
 pub(crate) use debug::*;
+pub(crate) use deprovision::*;
 pub(crate) use eval::*;
 pub(crate) use git::*;
 pub(crate) use misc::*;
+pub(crate) use new::*;
 pub(crate) use provision::*;
 pub(crate) use rebuild::*;
+pub(crate) use remote::*;
 pub(crate) use shell::*;
+pub(crate) use ssh_config::*;
 pub(crate) use store::*;
+pub(crate) use vm::*;
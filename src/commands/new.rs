@@ -0,0 +1,293 @@
+use std::fs;
+use std::path::Path;
+
+use users::get_current_username;
+
+use crate::SnowError;
+use crate::util::Result;
+
+use super::git_add;
+use super::util::{FlakeConfig, nixos_configuration_names};
+
+const DEFAULT_HOST_TEMPLATE: &str = r#"{ lib, ... }:
+{
+  imports = lib.optional (builtins.pathExists ./hardware-configuration.nix) ./hardware-configuration.nix;
+  snow.facterReport = lib.mkIf (builtins.pathExists ./facter.json) ./facter.json;
+
+  networking.hostName = "@name@";
+
+  snow.targetHost = "@targetHost@";
+  snow.sshUser = "@sshUser@";
+}
+"#;
+
+/// Used if neither the flake configures an entry nor an existing one can be copied. It expects
+/// the flake's inputs in scope as `inputs`, with snow among them.
+const DEFAULT_FLAKE_ENTRY: &str = "@name@ = inputs.nixpkgs.lib.nixosSystem { specialArgs = { inherit inputs; }; modules = [ inputs.snow.nixosModules.default ./hosts/@name@ ]; };";
+
+/// Fill in the placeholders of a host template or flake entry. `target` is in user@host
+/// format; without a user, the current one is assumed.
+fn fill_template(template: &str, name: &str, target: &str) -> String {
+    let (ssh_user, target_host) = match target.split_once('@') {
+        Some((user, host)) => (user.to_string(), host),
+        None => (
+            get_current_username()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            target,
+        ),
+    };
+    template
+        .replace("@name@", name)
+        .replace("@targetHost@", target_host)
+        .replace("@sshUser@", &ssh_user)
+}
+
+/// Offset of the first line inside a `nixosConfigurations = { ... }` set in flake.nix. `None` if
+/// there is no such set, e.g. because hosts are collected by a function.
+fn configurations_start(flake_nix: &str) -> Option<usize> {
+    let start = flake_nix.find("nixosConfigurations")?;
+    let rest = flake_nix[start + "nixosConfigurations".len()..].trim_start();
+    let rest = rest.strip_prefix('=')?.trim_start();
+    if !rest.starts_with('{') {
+        return None;
+    }
+    let brace = flake_nix.len() - rest.len();
+    Some(brace + flake_nix[brace..].find('\n')? + 1)
+}
+
+/// Insert `entry` as the first attribute of a `nixosConfigurations = { ... }` set in flake.nix.
+/// `None` if there is no such set.
+fn register_host(flake_nix: &str, entry: &str) -> Option<String> {
+    let line_end = configurations_start(flake_nix)?;
+
+    // Indent like the following attribute, or one level deeper than the opening line
+    let next_line = flake_nix[line_end..]
+        .lines()
+        .find(|x| !x.trim().is_empty())?;
+    let indentation = match next_line.trim_start().starts_with('}') {
+        true => {
+            let start = flake_nix.find("nixosConfigurations")?;
+            let line_start = flake_nix[..start].rfind('\n').map_or(0, |i| i + 1);
+            let opening = &flake_nix[line_start..];
+            format!(
+                "{}  ",
+                &opening[..opening.len() - opening.trim_start().len()]
+            )
+        }
+        false => next_line[..next_line.len() - next_line.trim_start().len()].to_string(),
+    };
+
+    let mut flake_nix = flake_nix.to_string();
+    flake_nix.insert_str(line_end, &format!("{indentation}{entry}\n"));
+    Some(flake_nix)
+}
+
+/// Turn the first attribute of the `nixosConfigurations` set into an entry template, so new hosts
+/// get the same modules, `specialArgs` and system as their neighbours. `None` if there is none,
+/// or it does not mention its own name, which would make the copy another instance of that host.
+fn neighbour_entry(flake_nix: &str) -> Option<String> {
+    let body = &flake_nix[configurations_start(flake_nix)?..];
+    let body = body.trim_start();
+    let name_len = body.find(|c: char| !is_identifier_char(c))?;
+    let name = &body[..name_len];
+    if name.is_empty() || name == "inherit" || !body[name_len..].trim_start().starts_with('=') {
+        return None;
+    }
+    let entry = &body[..=attribute_end(body)?];
+    let value = &entry[name_len..];
+    let mut template = "@name@".to_string();
+    let mut found = false;
+    let mut rest = value;
+    while let Some(i) = rest.find(name) {
+        let before = rest[..i].chars().next_back();
+        let after = rest[i + name.len()..].chars().next();
+        template.push_str(&rest[..i]);
+        match before.is_some_and(is_identifier_char) || after.is_some_and(is_identifier_char) {
+            true => template.push_str(name),
+            false => {
+                template.push_str("@name@");
+                found = true;
+            }
+        }
+        rest = &rest[i + name.len()..];
+    }
+    template.push_str(rest);
+    found.then_some(template)
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\'')
+}
+
+/// Offset of the `;` ending the attribute `source` starts with, skipping nested sets, lists,
+/// parentheses, strings and comments.
+fn attribute_end(source: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut chars = source.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' | '[' | '(' => depth += 1,
+            '}' | ']' | ')' => depth = depth.checked_sub(1)?,
+            ';' if depth == 0 => return Some(i),
+            '#' => {
+                chars.find(|(_, c)| *c == '\n');
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '\'' if chars.peek().is_some_and(|(_, c)| *c == '\'') => {
+                chars.next();
+                let mut quotes = 0;
+                for (_, c) in chars.by_ref() {
+                    quotes = if c == '\'' { quotes + 1 } else { 0 };
+                    if quotes == 2 {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Create `hosts/<name>/default.nix` from the host template of the flake, and add the host to
+/// `nixosConfigurations` unless the flake picks it up by itself.
+pub(crate) fn scaffold_host(name: &str, target: &str) -> Result<()> {
+    if nixos_configuration_names(false)?.contains(&name.to_string()) {
+        return Err(SnowError::Env(format!(
+            "nixosConfiguration \"{name}\" exists already"
+        )));
+    }
+    let host_dir = Path::new("hosts").join(name);
+    let host_file = host_dir.join("default.nix");
+    if host_file.exists() {
+        return Err(SnowError::Env(format!(
+            "{} exists already",
+            host_file.to_string_lossy()
+        )));
+    }
+
+    let new_host = FlakeConfig::get_flake_config()?.new_host;
+    let template = match new_host.template {
+        Some(ref template) => fs::read_to_string(template)?,
+        None => DEFAULT_HOST_TEMPLATE.to_string(),
+    };
+    fs::create_dir_all(&host_dir)?;
+    fs::write(&host_file, fill_template(&template, name, target))?;
+    log::info!("Created {}", host_file.to_string_lossy());
+    git_add(false)?;
+
+    if nixos_configuration_names(false)?.contains(&name.to_string()) {
+        log::info!("The flake picked up {name} by itself.");
+        return Ok(());
+    }
+    let flake_nix = fs::read_to_string("flake.nix")?;
+    let template = match new_host.entry {
+        Some(entry) => entry,
+        None => neighbour_entry(&flake_nix).unwrap_or_else(|| {
+            log::warn!("No existing nixosConfiguration to copy, check the entry added for {name}");
+            DEFAULT_FLAKE_ENTRY.to_string()
+        }),
+    };
+    let entry = fill_template(&template, name, target);
+    match register_host(&flake_nix, &entry) {
+        Some(flake_nix) => {
+            fs::write("flake.nix", flake_nix)?;
+            log::info!("Added {name} to nixosConfigurations in flake.nix");
+            git_add(false)
+        }
+        None => Err(SnowError::Env(format!(
+            "could not find a `nixosConfigurations = {{ ... }}` set in flake.nix, add {name} to it manually: {entry}"
+        ))),
+    }
+}
+
+pub(crate) fn new_host(name: &str, target: &str) -> Result<()> {
+    scaffold_host(name, target)?;
+    log::info!("Done! Deploy {name} with `snow rebuild {name}`.");
+    Ok(())
+}
+
+#[test]
+fn test_new_host() {
+    assert_eq!(
+        fill_template(
+            "@name@ on @targetHost@ as @sshUser@",
+            "web",
+            "admin@10.0.0.5"
+        ),
+        "web on 10.0.0.5 as admin"
+    );
+
+    let flake_nix = "{\n  outputs = inputs: {\n    nixosConfigurations = {\n      db = mkHost \"db\";\n    };\n  };\n}\n";
+    assert_eq!(
+        register_host(flake_nix, "web = mkHost \"web\";").unwrap(),
+        "{\n  outputs = inputs: {\n    nixosConfigurations = {\n      web = mkHost \"web\";\n      db = mkHost \"db\";\n    };\n  };\n}\n"
+    );
+    assert_eq!(
+        register_host("{\n  nixosConfigurations = {\n  };\n}\n", "web = 1;").unwrap(),
+        "{\n  nixosConfigurations = {\n    web = 1;\n  };\n}\n"
+    );
+    assert_eq!(
+        register_host(
+            "{\n  nixosConfigurations = mkHosts ./hosts;\n}\n",
+            "web = 1;"
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_neighbour_entry() {
+    let flake_nix = r#"{
+  outputs = { nixpkgs, snow, ... }@inputs: {
+    nixosConfigurations = {
+      db = nixpkgs.lib.nixosSystem {
+        system = "x86_64-linux";
+        specialArgs = { inherit inputs; hostName = "db"; }; # the db; host
+        modules = [ snow.nixosModules.default ./hosts/db ./dbx ];
+      };
+      dbx = nixpkgs.lib.nixosSystem { modules = [ ./hosts/dbx ]; };
+    };
+  };
+}
+"#;
+    let entry = neighbour_entry(flake_nix).unwrap();
+    assert_eq!(
+        entry,
+        r#"@name@ = nixpkgs.lib.nixosSystem {
+        system = "x86_64-linux";
+        specialArgs = { inherit inputs; hostName = "@name@"; }; # the @name@; host
+        modules = [ snow.nixosModules.default ./hosts/@name@ ./dbx ];
+      };"#
+    );
+
+    // The new entry is inserted with the shape of its neighbour
+    let flake_nix = register_host(flake_nix, &fill_template(&entry, "web", "10.0.0.5")).unwrap();
+    let web = &flake_nix[flake_nix.find("      web = ").unwrap()..];
+    let db = &flake_nix[flake_nix.find("      db = ").unwrap()..];
+    assert_eq!(
+        web[..=attribute_end(web).unwrap()].replace("web", "db"),
+        db[..=attribute_end(db).unwrap()]
+    );
+
+    assert_eq!(
+        neighbour_entry("{\n  nixosConfigurations = {\n    db = mkHost { };\n  };\n}\n"),
+        None
+    );
+    assert_eq!(
+        neighbour_entry("{\n  nixosConfigurations = {\n  };\n}\n"),
+        None
+    );
+}
@@ -1,221 +1,209 @@
 use crate::{
-    RebuildMode, Result, SnowError, agenix_rekey,
+    ProvisionStep, RebuildMode, Result, SnowError, agenix_rekey,
     commands::{
         runners::SnowCommand,
-        util::{SnowConfig, VmConfigResolved, wrap},
+        util::{
+            HostProbe, Hypervisor, SnowConfig, VmConfigResolved, VmKind, VmSource, WaitOptions,
+            WaitStage, allocate_vm, check_rekey_host_key, cloud_init_settings, extend_nix_sshopts,
+            generate_host_key, host_key_state_path, hypervisor, known_hosts_args,
+            restore_nix_sshopts, same_public_key, state_dir, verify_deployment, vm_host_key_path,
+            wrap, write_host_key,
+        },
     },
-    git_add, rebuild,
+    git_add, known_hosts, rebuild, ssh_login,
 };
-use std::{thread, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use strum::IntoEnumIterator;
+
+const KEYSCAN_RETRY_INTERVAL: Duration = Duration::from_secs(2);
 
-trait OrCleanup {
-    fn or_cleanup(self) -> Self;
+/// Progress of a provisioning run, kept outside of the flake so a failed run can be resumed.
+#[derive(Deserialize, Serialize, Default)]
+struct ProvisionState {
+    completed: Option<ProvisionStep>,
+    archive_name: Option<String>,
+    archive: Option<String>,
 }
-impl<T> OrCleanup for crate::Result<T> {
-    fn or_cleanup(self) -> Self {
-        if self.is_ok() {
-            return self;
+
+impl ProvisionState {
+    fn path(vm_configuration: &str) -> Result<PathBuf> {
+        Ok(state_dir("provision")?.join(format!("{vm_configuration}.json")))
+    }
+
+    fn load(vm_configuration: &str) -> Result<Option<Self>> {
+        match std::fs::read_to_string(Self::path(vm_configuration)?) {
+            Ok(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, vm_configuration: &str) -> Result<()> {
+        std::fs::write(
+            Self::path(vm_configuration)?,
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    fn remove(vm_configuration: &str) -> Result<()> {
+        match std::fs::remove_file(Self::path(vm_configuration)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
         }
-        cleanup()?;
-        self
     }
 }
 
-fn cleanup() -> Result<()> {
-    std::fs::remove_dir_all("result")?;
-    Ok(())
+/// Which variant of provisioning runs, deciding the steps that are part of it.
+#[derive(Clone, Copy)]
+struct ProvisionFlow {
+    inject_host_key: bool,
+    from_template: bool,
+    container: bool,
+}
+
+impl ProvisionFlow {
+    /// Whether a step is part of provisioning. With an injected host key, secrets are rekeyed
+    /// for the real key right away, so the VM needs no dummy secrets. It is still rebuilt and
+    /// rebooted, as the image boots its image variant of the system rather than the toplevel.
+    /// Cloned templates get their configuration by the rebuild, not through an image. Resizing
+    /// a container grows its filesystem along with the volume.
+    fn applies(&self, step: ProvisionStep) -> bool {
+        match step {
+            ProvisionStep::GenerateKey | ProvisionStep::InjectKey | ProvisionStep::RemoveKey => {
+                self.inject_host_key
+            }
+            ProvisionStep::Rekey => !self.inject_host_key,
+            ProvisionStep::DummyRekey => !self.inject_host_key && !self.from_template,
+            ProvisionStep::Build
+            | ProvisionStep::Upload
+            | ProvisionStep::Restore
+            | ProvisionStep::Cleanup => !self.from_template,
+            ProvisionStep::Clone => self.from_template,
+            ProvisionStep::ResizeFs => !self.container,
+            _ => true,
+        }
+    }
+
+    /// The step to start at: the one given explicitly, the one following the last completed
+    /// step when resuming, or the very first one. `None` if there is nothing left to do.
+    fn first_step(
+        &self,
+        resume: bool,
+        from_step: Option<ProvisionStep>,
+        completed: Option<ProvisionStep>,
+    ) -> Option<ProvisionStep> {
+        let mut steps = ProvisionStep::iter().filter(|step| self.applies(*step));
+        match (from_step, resume, completed) {
+            (Some(step), _, _) => Some(step),
+            (None, true, Some(completed)) => steps.find(|step| *step > completed),
+            _ => steps.next(),
+        }
+    }
+}
+
+/// Locate the image built for the VM. Container tarballs are placed in a subdirectory.
+fn find_image(extension: &str) -> Result<PathBuf> {
+    ["result", "result/tarball"]
+        .into_iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .find(|path| path.to_string_lossy().ends_with(extension))
+        .ok_or_else(|| {
+            SnowError::Env(
+                "the VM image is missing, run again with `--from-step build`".to_string(),
+            )
+        })
+}
+
+fn remove_result() -> Result<()> {
+    match std::fs::remove_dir_all("result") {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
 }
 
 pub(crate) fn provision(
     vm_configuration: &str,
     login_after: bool,
     rebuild_host: bool,
+    resume: bool,
+    from_step: Option<ProvisionStep>,
+    wait_timeout: u64,
+    wait_for_system: bool,
 ) -> crate::Result<()> {
-    let snow_config = SnowConfig::get_snow_config(vm_configuration)?;
-    let vm_config: VmConfigResolved = match snow_config.vm {
-        Some(vm_config) => vm_config.try_into()?,
-        None => {
-            return Err(SnowError::SnowConfig(format!(
-                "VM settings are not configured for host \"{vm_configuration}\""
-            )));
-        }
+    // Hand out an id and address first if the host leaves them to the pools of the flake
+    if allocate_vm(vm_configuration)? {
+        git_add(false)?;
+    }
+    let (snow_config, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    let hypervisor = hypervisor(vm_configuration, &vm_config)?;
+    let probe = HostProbe {
+        name: vm_configuration,
+        // The host key is only recorded in the Keyscan step, so trust `snow known-hosts` up front
+        ssh_args: snow_config.ssh_command_trusting(vm_configuration, &[], true),
+        options: WaitOptions {
+            timeout: Duration::from_secs(wait_timeout),
+            system_running: wait_for_system,
+        },
     };
 
-    // Rekey secrets with a dummy kwy for the new host
-    agenix_rekey(false, true)?;
-    git_add(false)?;
-
-    // Generate the vm through nix build
-    let command = SnowCommand::new_nix(
-        "nix".to_string(),
-        vec![
-            "build",
-            &wrap(
-                &format!(
-                    "nixosConfigurations.{}.config.system.build.images.proxmox",
-                    vm_configuration
-                ),
-                true,
-            ),
-        ],
-        false,
-    );
-    command
-        .run_progress(vm_configuration.to_string())
-        .or_cleanup()?;
-
-    // Copy the result to the proxmox iso dir
-    let command = SnowCommand::new(
-        "cp".to_string(),
-        vec![
-            &format!("result/vzdump-qemu-{}.vma.zst", vm_configuration),
-            &format!(
-                "{}/vzdump-qemu-{}-2024_06_01-10_00_00.vma.zst",
-                vm_config.proxmox_image_store, vm_config.id
-            ),
-        ],
-        false,
-    );
-    log::info!("Copying the VM image to the proxmox host...");
-    command.run_verbose().or_cleanup()?;
-
-    // Import the VM on the proxmox host
-    let command = SnowCommand::new(
-        "ssh".to_string(),
-        vec![
-            &vm_config.proxmox_host,
-            &format!(
-                "qmrestore /mnt/pve/proxmox_images/template/iso/vzdump-qemu-{}-2024_06_01-10_00_00.vma.zst {} --unique true",
-                vm_config.id, vm_config.id
-            ),
-        ],
-        false,
-    );
-    command.run_progress_import().or_cleanup()?;
-
-    //Remove no-longer needed files
-    log::info!("Performing cleanup tasks...");
-    std::fs::remove_dir_all("result")?;
-    std::fs::remove_file(format!(
-        "{}/vzdump-qemu-{}-2024_06_01-10_00_00.vma.zst",
-        vm_config.proxmox_image_store, vm_config.id
-    ))?;
-
-    // Resize disks according to nix vm config
-    let command = SnowCommand::new(
-        "ssh".to_string(),
-        vec![
-            &vm_config.proxmox_host,
-            &format!(
-                "qm disk resize {} virtio0 {}",
-                vm_config.id, vm_config.resize_disk_to
-            ),
-        ],
-        false,
-    );
-    log::info!(
-        "Increasing disk size of disk \"vm_datastore:vm-{}-disk-0\" (virtio0) to {}...",
-        vm_config.id,
-        vm_config.resize_disk_to
-    );
-    command.run_silent().or_cleanup()?;
-
-    // Boot the VM for the first time
-    let command = SnowCommand::new(
-        "ssh".to_string(),
-        vec![
-            &vm_config.proxmox_host,
-            &format!("qm start {}", vm_config.id),
-        ],
-        false,
-    );
-    command.run_silent().or_cleanup()?;
-
-    // Remove any stale known_hosts entries so re-provisioning doesn't fail on key mismatch
-    let _ =
-        SnowCommand::new("ssh-keygen".to_string(), vec!["-R", &vm_config.ip], false).run_silent();
-    if let Some(ref target_host) = snow_config.target_host {
-        let _ =
-            SnowCommand::new("ssh-keygen".to_string(), vec!["-R", target_host], false).run_silent();
-    }
-
-    // Obtain the public key
-    let command = SnowCommand::new("ssh-keyscan".to_string(), vec![&vm_config.ip], false);
-    log::info!("Waiting for {vm_configuration} to come online to obtain its public ssh key...");
-    let pub_key = loop {
-        // Give machine time to boot
-        thread::sleep(Duration::from_millis(5000));
-        if let Some(key) = command
-            .run_with_return()
-            .or_cleanup()?
-            .lines()
-            .find(|line| line.starts_with(&vm_config.ip) && line.contains("ssh-ed25519"))
-        {
-            break format!(
-                "{} {}",
-                key.replace(&vm_config.ip, "").trim_start(),
-                vm_configuration
-            );
-        };
+    let previous = ProvisionState::load(vm_configuration)?;
+    let completed = previous.as_ref().and_then(|state| state.completed);
+    if resume && previous.is_none() {
+        log::info!("No previous provisioning run of {vm_configuration} found, starting over.");
+    }
+    let mut state = match resume || from_step.is_some() {
+        true => previous.unwrap_or_default(),
+        false => ProvisionState::default(),
     };
 
-    // Save pubkey and add to git
-    std::fs::write(
-        format!("vms/keys/ssh_host_{vm_configuration}_ed25519_key.pub"),
-        pub_key,
-    )?;
-    git_add(false)?;
-
-    // Rekey secrets for the new host, with real keys this time
-    agenix_rekey(false, false)?;
-    git_add(false)?;
-
-    // Rebuild the host, with correct secrets this time
-    unsafe {
-        std::env::set_var(
-            "NIX_SSHOPTS",
-            "-o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null",
-        );
+    let flow = ProvisionFlow {
+        inject_host_key: vm_config.inject_host_key,
+        from_template: matches!(vm_config.source, VmSource::Template { .. }),
+        container: vm_config.kind == VmKind::Lxc,
+    };
+    if flow.inject_host_key && flow.from_template {
+        return Err(SnowError::SnowConfig(
+            "injectHostKey cannot be used with VMs cloned from a template".to_string(),
+        ));
     }
-    rebuild(
-        &Some(vm_configuration.to_string()),
-        &RebuildMode::Boot,
-        &None,
-        &None,
-        false,
-        false,
-        false,
-        &None,
-    )?;
-    unsafe {
-        std::env::set_var("NIX_SSHOPTS", "");
-    }
-
-    // Reboot the VM so the new config with secret keys can become active
-    let command = SnowCommand::new(
-        "ssh".to_string(),
-        vec![
-            &vm_config.proxmox_host,
-            &format!("qm reboot {}", vm_config.id),
-        ],
-        false,
-    );
-    log::info!("Rebooting {vm_configuration}...");
-    command.run_silent()?;
-
-    // Auto-accept the new VM key, and use the opportunity to run resize2fs
-    let command = SnowCommand::new(
-        "ssh".to_string(),
-        vec![
-            "-o",
-            "StrictHostKeyChecking=accept-new",
-            &snow_config.target_host.clone().unwrap(),
-            "sudo",
-            "resize2fs /dev/vda2",
-        ],
-        false,
-    );
-    command.run_silent()?;
+    if flow.container && (flow.inject_host_key || flow.from_template) {
+        return Err(SnowError::SnowConfig(
+            "containers support neither injectHostKey nor cloning templates".to_string(),
+        ));
+    }
+    if let Some(first) = flow.first_step(resume, from_step, completed) {
+        if first != flow.first_step(false, None, None).unwrap() {
+            log::info!("Continuing to provision {vm_configuration} from step \"{first}\"...");
+        }
+        for step in ProvisionStep::iter().filter(|step| *step >= first && flow.applies(*step)) {
+            log::debug!("provision step \"{step}\"");
+            if let Err(e) = run_step(
+                step,
+                vm_configuration,
+                &snow_config,
+                &vm_config,
+                hypervisor.as_ref(),
+                &probe,
+                &mut state,
+            ) {
+                log::info!(
+                    "Provisioning stopped at step \"{step}\". Run `snow provision {vm_configuration} --resume` to continue from there."
+                );
+                return Err(e);
+            }
+            state.completed = Some(step);
+            state.save(vm_configuration)?;
+        }
+    }
+    ProvisionState::remove(vm_configuration)?;
 
     // OPTIONALLY: rebuild the local host to make its SSH handle available
     if rebuild_host {
@@ -233,14 +221,360 @@ pub(crate) fn provision(
 
     // OPTIONALLY: log the user into the new VM via SSH
     if login_after {
+        ssh_login(vm_configuration, &[])?;
+    }
+    log::info!("Done!");
+
+    Ok(())
+}
+
+fn run_step(
+    step: ProvisionStep,
+    vm_configuration: &str,
+    snow_config: &SnowConfig,
+    vm_config: &VmConfigResolved,
+    hypervisor: &dyn Hypervisor,
+    probe: &HostProbe,
+    state: &mut ProvisionState,
+) -> Result<()> {
+    match step {
+        // Rekey secrets with a dummy kwy for the new host
+        ProvisionStep::DummyRekey => {
+            agenix_rekey(false, true)?;
+            git_add(false)
+        }
+
+        // Generate the host key locally, and rekey secrets for it
+        ProvisionStep::GenerateKey => {
+            let key = host_key_state_path(vm_configuration)?;
+            log::info!("Generating the host key of {vm_configuration}...");
+            generate_host_key(&key, vm_configuration)?;
+            let host_key = vm_host_key_path(vm_configuration)?;
+            write_host_key(
+                &host_key,
+                &std::fs::read_to_string(format!("{}.pub", key.display()))?,
+            )?;
+            git_add(false)?;
+            check_rekey_host_key(vm_configuration, &host_key)?;
+            known_hosts(&None)?;
+            agenix_rekey(false, false)?;
+            git_add(false)
+        }
+
+        // Generate the vm through nix build
+        ProvisionStep::Build => {
+            let command = SnowCommand::new_nix(
+                "nix".to_string(),
+                vec![
+                    "build",
+                    &wrap(
+                        &format!(
+                            "nixosConfigurations.{}.config.system.build.images.{}",
+                            vm_configuration,
+                            hypervisor.image_variant()
+                        ),
+                        true,
+                    ),
+                ],
+                false,
+            );
+            command.run_progress(vm_configuration.to_string())
+        }
+
+        // Make the image available to the hypervisor
+        ProvisionStep::Upload => {
+            let image = find_image(hypervisor.image_extension())?;
+            let archive_name = hypervisor.archive_name();
+            state.archive = Some(hypervisor.upload_image(&image, &archive_name)?);
+            state.archive_name = Some(archive_name);
+            Ok(())
+        }
+
+        // Import the VM on the hypervisor
+        ProvisionStep::Restore => {
+            let Some(ref archive) = state.archive else {
+                return Err(SnowError::Env(
+                    "no uploaded image is known, run again with `--from-step upload`".to_string(),
+                ));
+            };
+            hypervisor.restore(archive, vm_config.target_storage.as_deref())
+        }
+
+        // Clone the template instead of building and restoring an image
+        ProvisionStep::Clone => {
+            let VmSource::Template { template_id } = vm_config.source else {
+                return Err(SnowError::SnowConfig(format!(
+                    "{vm_configuration} is not created from a template"
+                )));
+            };
+            log::info!("Cloning template {template_id}...");
+            hypervisor.clone_template(
+                template_id,
+                vm_configuration,
+                vm_config.target_storage.as_deref(),
+            )
+        }
+
+        // Apply the declared hardware settings, and the address of cloned templates
+        ProvisionStep::Configure => {
+            hypervisor.apply_hardware(&vm_config.hardware)?;
+            if let VmSource::Template { .. } = vm_config.source {
+                hypervisor.set(&cloud_init_settings(
+                    vm_config.require_ip()?,
+                    vm_config.prefix_length,
+                    vm_config.gateway.as_deref(),
+                    &vm_config.nameservers,
+                ))?;
+            }
+            Ok(())
+        }
+
+        // Hand the host key to the VM for its first boot
+        ProvisionStep::InjectKey => {
+            hypervisor.inject_host_key(&host_key_state_path(vm_configuration)?)
+        }
+
+        //Remove no-longer needed files
+        ProvisionStep::Cleanup => {
+            log::info!("Performing cleanup tasks...");
+            remove_result()?;
+            if let Some(archive_name) = state.archive_name.take() {
+                hypervisor.remove_image(&archive_name)?;
+            }
+            state.archive = None;
+            Ok(())
+        }
+
+        // Resize disks according to nix vm config
+        ProvisionStep::Resize => {
+            log::info!(
+                "Increasing the size of disk {} of {} to {}...",
+                vm_config.disk,
+                hypervisor.vm_name(),
+                vm_config.require_resize_disk_to()?
+            );
+            hypervisor.resize_disk(&vm_config.disk, vm_config.require_resize_disk_to()?)
+        }
+
+        // Boot the VM for the first time
+        ProvisionStep::Start => hypervisor.start(),
+
+        // Obtain the public key, save it and add to git, and trust it from now on. An injected
+        // key is known already, and only checked to have been picked up.
+        ProvisionStep::Keyscan => {
+            log::info!(
+                "Waiting for {vm_configuration} to come online to obtain its public ssh key..."
+            );
+            let deadline = Instant::now() + probe.options.timeout;
+            probe.wait(WaitStage::Banner, None)?;
+            let pub_key = scan_host_key(vm_configuration, vm_config.require_ip()?, deadline)?;
+            if vm_config.inject_host_key {
+                let expected = std::fs::read_to_string(vm_host_key_path(vm_configuration)?)?;
+                if !same_public_key(&expected, &pub_key) {
+                    return Err(SnowError::Env(format!(
+                        "{vm_configuration} did not pick up the injected host key"
+                    )));
+                }
+                return Ok(());
+            }
+            let host_key = vm_host_key_path(vm_configuration)?;
+            write_host_key(&host_key, &pub_key)?;
+            git_add(false)?;
+            check_rekey_host_key(vm_configuration, &host_key)?;
+            known_hosts(&None)
+        }
+
+        // Rekey secrets for the new host, with real keys this time
+        ProvisionStep::Rekey => {
+            agenix_rekey(false, false)?;
+            git_add(false)
+        }
+
+        // Rebuild the host, with correct secrets this time
+        ProvisionStep::Rebuild => {
+            let previous_sshopts = extend_nix_sshopts(&known_hosts_args())?;
+            let result = rebuild(
+                &Some(vm_configuration.to_string()),
+                &RebuildMode::Boot,
+                &None,
+                &None,
+                false,
+                false,
+                false,
+                &None,
+            );
+            restore_nix_sshopts(previous_sshopts);
+            result
+        }
+
+        // Reboot the VM so the new config with secret keys can become active
+        ProvisionStep::Reboot => {
+            log::info!("Rebooting {vm_configuration}...");
+            let boot_id = probe.boot_id();
+            hypervisor.reboot()?;
+            probe.wait(probe.options.ready_stage(), boot_id.as_deref())
+        }
+
+        // Grow the root filesystem into the resized disk
+        ProvisionStep::ResizeFs => {
+            probe.wait(probe.options.ready_stage(), None)?;
+            let ssh_args = snow_config.ssh_command_for(
+                vm_configuration,
+                &["sudo", "resize2fs", &vm_config.root_partition],
+            );
+            let command = SnowCommand::new(
+                "ssh".to_string(),
+                ssh_args.iter().map(|x| x.as_str()).collect(),
+                false,
+            );
+            command.run_silent()
+        }
+
+        // The VM has its host key on disk now, no copies need to stay around
+        ProvisionStep::RemoveKey => {
+            hypervisor.remove_host_key()?;
+            let key = host_key_state_path(vm_configuration)?;
+            std::fs::remove_file(PathBuf::from(format!("{}.pub", key.display())))?;
+            std::fs::remove_file(key)?;
+            Ok(())
+        }
+
+        // Make sure the VM came back with the system and secrets it was given
+        ProvisionStep::Verify => {
+            probe.wait(probe.options.ready_stage(), None)?;
+            verify_deployment(probe, vm_configuration)
+        }
+    }
+}
+
+/// Obtain the ed25519 host key of the VM, retrying until `deadline` as sshd may not answer yet
+/// even though the VM is reachable, e.g. through a jump host whose banner cannot be waited for.
+fn scan_host_key(vm_configuration: &str, ip: &str, deadline: Instant) -> Result<String> {
+    loop {
         let command = SnowCommand::new(
-            "ssh".to_string(),
-            vec![&snow_config.target_host.unwrap()],
+            "ssh-keyscan".to_string(),
+            vec!["-T", "5", "-t", "ed25519", ip],
             false,
         );
-        command.run_silent()?;
+        let (_, output) = command.run_with_status()?;
+        if let Some(pub_key) = parse_keyscan(&output, ip, vm_configuration) {
+            return Ok(pub_key);
+        }
+        if Instant::now() >= deadline {
+            return Err(SnowError::Env(format!(
+                "{vm_configuration} did not offer an ed25519 host key"
+            )));
+        }
+        log::debug!("{vm_configuration} did not offer a host key yet, retrying");
+        std::thread::sleep(KEYSCAN_RETRY_INTERVAL);
     }
-    log::info!("Done!");
+}
 
-    Ok(())
+/// The ed25519 key in `ssh-keyscan` output for `ip`, commented with the configuration name.
+fn parse_keyscan(output: &str, ip: &str, vm_configuration: &str) -> Option<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix(ip)?.strip_prefix(' '))
+        .find(|key| key.starts_with("ssh-ed25519 "))
+        .map(|key| format!("{} {vm_configuration}", key.trim()))
+}
+
+#[test]
+fn test_first_step() {
+    let flow = ProvisionFlow {
+        inject_host_key: false,
+        from_template: false,
+        container: false,
+    };
+    assert_eq!(
+        flow.first_step(false, None, Some(ProvisionStep::Rekey)),
+        Some(ProvisionStep::DummyRekey)
+    );
+    assert_eq!(
+        flow.first_step(true, None, Some(ProvisionStep::Rekey)),
+        Some(ProvisionStep::Rebuild)
+    );
+    assert_eq!(
+        flow.first_step(true, None, None),
+        Some(ProvisionStep::DummyRekey)
+    );
+    assert_eq!(
+        flow.first_step(true, None, Some(ProvisionStep::ResizeFs)),
+        Some(ProvisionStep::Verify)
+    );
+    assert_eq!(
+        flow.first_step(true, None, Some(ProvisionStep::Verify)),
+        None
+    );
+    assert_eq!(
+        flow.first_step(
+            true,
+            Some(ProvisionStep::Upload),
+            Some(ProvisionStep::Rekey)
+        ),
+        Some(ProvisionStep::Upload)
+    );
+
+    let flow = ProvisionFlow {
+        inject_host_key: true,
+        from_template: false,
+        container: false,
+    };
+    assert_eq!(
+        flow.first_step(false, None, None),
+        Some(ProvisionStep::GenerateKey)
+    );
+    assert_eq!(
+        flow.first_step(true, None, Some(ProvisionStep::Keyscan)),
+        Some(ProvisionStep::Rebuild)
+    );
+    assert!(flow.applies(ProvisionStep::Reboot));
+
+    let flow = ProvisionFlow {
+        inject_host_key: false,
+        from_template: true,
+        container: false,
+    };
+    assert_eq!(
+        flow.first_step(false, None, None),
+        Some(ProvisionStep::Clone)
+    );
+    assert_eq!(
+        ProvisionStep::iter()
+            .filter(|step| flow.applies(*step))
+            .collect::<Vec<_>>(),
+        vec![
+            ProvisionStep::Clone,
+            ProvisionStep::Configure,
+            ProvisionStep::Resize,
+            ProvisionStep::Start,
+            ProvisionStep::Keyscan,
+            ProvisionStep::Rekey,
+            ProvisionStep::Rebuild,
+            ProvisionStep::Reboot,
+            ProvisionStep::ResizeFs,
+            ProvisionStep::Verify,
+        ]
+    );
+
+    let flow = ProvisionFlow {
+        inject_host_key: false,
+        from_template: false,
+        container: true,
+    };
+    assert_eq!(
+        flow.first_step(true, None, Some(ProvisionStep::Reboot)),
+        Some(ProvisionStep::Verify)
+    );
+}
+
+#[test]
+fn test_parse_keyscan() {
+    let output = "10.0.0.50 ssh-rsa AAAAB3Nza\n10.0.0.50 ssh-ed25519 AAAAC3Nza\n";
+    assert_eq!(
+        parse_keyscan(output, "10.0.0.50", "web"),
+        Some("ssh-ed25519 AAAAC3Nza web".to_string())
+    );
+    assert_eq!(parse_keyscan(output, "10.0.0.5", "web"), None);
+    assert_eq!(parse_keyscan("", "10.0.0.50", "web"), None);
 }
@@ -7,7 +7,7 @@ use log::LevelFilter;
 use users::get_current_username;
 
 use super::runners::SnowCommand;
-use super::util::SnowConfig;
+use super::util::{SnowConfig, extend_nix_sshopts, restore_nix_sshopts};
 use super::{RebuildMode, exist_untracked, git_add};
 
 #[allow(clippy::too_many_arguments)]
@@ -40,7 +40,7 @@ pub(crate) fn rebuild(
         Some(nixos_configuration) => nixos_configuration,
         None => &hostname,
     };
-    let (args, sudo, effective_port) = {
+    let (args, sudo, ssh_args) = {
         let default_snow_config = SnowConfig::get_snow_config(nixos_configuration)?;
 
         // Resolve which build host to pass to nixos-rebuild.
@@ -107,6 +107,10 @@ pub(crate) fn rebuild(
                 .clone()
                 .or(default_snow_config.target_host.to_owned()),
             target_port: target_port.or(default_snow_config.target_port),
+            ssh_user: default_snow_config.ssh_user.clone(),
+            ssh_identity_file: default_snow_config.ssh_identity_file.clone(),
+            ssh_jump_host: default_snow_config.ssh_jump_host.clone(),
+            ssh_options: default_snow_config.ssh_options.clone(),
             vm: None,
         };
 
@@ -143,18 +147,18 @@ pub(crate) fn rebuild(
             wrap(nixos_configuration, true),
         ];
 
-        if let Some(ref target_host) = snow_config.target_host {
+        if let Some(target_host) = snow_config.ssh_destination() {
             if !build_only {
                 args.push("--target-host".to_string());
-                args.push(target_host.to_string());
+                args.push(target_host);
             } else {
                 log::debug!("in build-only mode, the --target-host arg is skipped");
             }
         }
 
-        if let Some(build_host) = snow_config.build_host {
+        if let Some(ref build_host) = snow_config.build_host {
             args.push("--build-host".to_string());
-            args.push(build_host);
+            args.push(build_host.clone());
         }
 
         if snow_config.use_remote_sudo {
@@ -168,17 +172,10 @@ pub(crate) fn rebuild(
         }
 
         let requires_sudo = *nixos_configuration == hostname && !build_only;
-        (args, requires_sudo, snow_config.target_port)
+        (args, requires_sudo, snow_config.ssh_args())
     };
 
-    if let Some(port) = effective_port {
-        let mut opts = std::env::var("NIX_SSHOPTS").unwrap_or_default();
-        if !opts.is_empty() {
-            opts += " ";
-        }
-        opts += &format!("-p {port}");
-        unsafe { std::env::set_var("NIX_SSHOPTS", opts) };
-    }
+    let previous_sshopts = extend_nix_sshopts(&ssh_args)?;
 
     let mut command = SnowCommand::new_nix(
         "nixos-rebuild".to_string(),
@@ -186,14 +183,15 @@ pub(crate) fn rebuild(
         sudo,
     );
 
-    match LOG_LEVEL.get() {
+    let result = match LOG_LEVEL.get() {
         Some(LevelFilter::Debug) => {
             command.append_arg("--show-trace");
-            command.run_verbose()?
+            command.run_verbose()
         }
-        _ => command.run_progress(nixos_configuration.to_string())?,
-    }
-    Ok(())
+        _ => command.run_progress(nixos_configuration.to_string()),
+    };
+    restore_nix_sshopts(previous_sshopts);
+    result
 }
 
 pub(crate) fn home(home_configuration: &Option<String>) -> Result<()> {
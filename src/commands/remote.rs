@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::thread;
+
+use crate::{SnowError, util::Result};
+
+use super::runners::SnowCommand;
+use super::util::{SnowConfig, hosts_with_tag, nixos_configuration_names};
+
+/// Expand a list of hosts and @tags into the hosts they refer to, keeping their order.
+fn resolve_targets(targets: &[String]) -> Result<Vec<String>> {
+    let known_hosts = nixos_configuration_names(false)?;
+    let mut hosts: Vec<String> = vec![];
+    for target in targets {
+        let expanded = match target.strip_prefix('@') {
+            Some(tag) => {
+                let tagged = hosts_with_tag(tag)?;
+                if tagged.is_empty() {
+                    return Err(SnowError::SnowConfig(format!(
+                        "no host is tagged \"{tag}\""
+                    )));
+                }
+                tagged
+            }
+            None if known_hosts.contains(target) => vec![target.clone()],
+            None => {
+                return Err(SnowError::SnowConfig(format!(
+                    "unknown host \"{target}\", it is not a nixosConfiguration"
+                )));
+            }
+        };
+        for host in expanded {
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+    }
+    Ok(hosts)
+}
+
+/// Open an interactive SSH session on the given host, or run `command` there.
+pub(crate) fn ssh_login(nixos_configuration: &str, command: &[String]) -> Result<()> {
+    let snow_config = SnowConfig::get_snow_config(nixos_configuration)?;
+    let ssh_args = snow_config.ssh_command_for(
+        nixos_configuration,
+        &command.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+    );
+    SnowCommand::new(
+        "ssh".to_string(),
+        ssh_args.iter().map(|x| x.as_str()).collect(),
+        false,
+    )
+    .run_interactive()
+}
+
+/// Run `command` on all given hosts in parallel, printing their output prefixed with the host
+/// name, followed by a summary of all exit codes.
+pub(crate) fn exec(targets: &[String], command: &[String]) -> Result<()> {
+    let hosts = resolve_targets(targets)?;
+    let mut snow_configs = SnowConfig::get_all_snow_configs()?;
+    let remote_command = command.join(" ");
+
+    let handles: Vec<_> = hosts
+        .into_iter()
+        .map(|host| {
+            let snow_config = snow_configs.remove(&host).unwrap_or_default();
+            let mut ssh_args = vec!["-o".to_string(), "BatchMode=yes".to_string()];
+            ssh_args.extend(snow_config.ssh_command_for(&host, &[&remote_command]));
+            thread::spawn(move || {
+                let exit_code = SnowCommand::new(
+                    "ssh".to_string(),
+                    ssh_args.iter().map(|x| x.as_str()).collect(),
+                    false,
+                )
+                .run_prefixed(&host);
+                (host, exit_code)
+            })
+        })
+        .collect();
+
+    let results: BTreeMap<String, Result<i32>> =
+        handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+    log::info!("Summary:");
+    let mut failed = 0;
+    for (host, result) in &results {
+        match result {
+            Ok(0) => log::info!("  {host}: exit code 0"),
+            Ok(code) => {
+                failed += 1;
+                log::error!("  {host}: exit code {code}");
+            }
+            Err(e) => {
+                failed += 1;
+                log::error!("  {host}: {e}");
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(SnowError::Env(format!(
+            "command failed on {failed} of {} hosts",
+            results.len()
+        ))),
+    }
+}
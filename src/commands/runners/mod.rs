@@ -1,4 +1,5 @@
 mod interactive;
+mod prefixed;
 mod progress;
 mod silent;
 mod verbose;
@@ -0,0 +1,43 @@
+use crate::util::Result;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use super::SnowCommand;
+
+impl SnowCommand {
+    /// Run the command without stdin, printing every line of its output prefixed with `prefix`.
+    /// Returns the exit code of the command.
+    pub(crate) fn run_prefixed(&self, prefix: &str) -> Result<i32> {
+        self.log();
+
+        let (command, args) = self.get_final_args();
+        let mut child = Command::new(command)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let style = anstyle::AnsiColor::Cyan.on_default();
+        let prefix = format!("{style}[{prefix}]{style:#}");
+
+        let lines_err = BufReader::new(child.stderr.take().unwrap()).lines();
+        let prefix_err = prefix.clone();
+        let handle_err = thread::spawn(move || {
+            for line in lines_err.map_while(|line| line.ok()) {
+                eprintln!("{prefix_err} {line}");
+            }
+        });
+        for line in BufReader::new(child.stdout.take().unwrap())
+            .lines()
+            .map_while(|line| line.ok())
+        {
+            println!("{prefix} {line}");
+        }
+        handle_err.join().unwrap();
+
+        Ok(child.wait()?.code().unwrap_or(-1))
+    }
+}
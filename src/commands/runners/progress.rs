@@ -43,7 +43,9 @@ impl SnowCommand {
                 }
             };
             let mut progress = progress_refresh.lock().unwrap();
-            progress.cleanup(exit_status.unwrap().unwrap()).unwrap();
+            progress
+                .cleanup(exit_status.unwrap().unwrap().success())
+                .unwrap();
             std::mem::drop(progress);
         });
 
@@ -117,7 +119,9 @@ impl SnowCommand {
                 }
             };
             let mut progress = progress_refresh.lock().unwrap();
-            progress.cleanup(exit_status.unwrap().unwrap()).unwrap();
+            progress
+                .cleanup(exit_status.unwrap().unwrap().success())
+                .unwrap();
             std::mem::drop(progress);
         });
 
@@ -40,6 +40,21 @@ impl SnowCommand {
         Ok(buf)
     }
 
+    /// Run the command quietly, returning whether it succeeded along with its stdout.
+    pub(crate) fn run_with_status(&self) -> Result<(bool, String)> {
+        self.log();
+        let (command, args) = self.get_final_args();
+        let output = Command::new(command)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()?;
+        Ok((
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).to_string(),
+        ))
+    }
+
     pub(crate) fn run_with_return_hash(&self) -> Result<String> {
         self.log();
         let (command, args) = self.get_final_args();
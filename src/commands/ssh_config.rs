@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::util::Result;
+
+use super::util::{
+    SnowConfig, repo_host_keys, snow_known_hosts_path, snow_ssh_config_path, split_destination,
+};
+
+/// The address under which a host is reached: its target host, falling back to the VM ip.
+fn host_address(snow_config: &SnowConfig) -> Option<&str> {
+    snow_config
+        .target_host
+        .as_deref()
+        .map(|target_host| split_destination(target_host).1)
+        .or(snow_config.vm.as_ref().and_then(|vm| vm.ip.as_deref()))
+}
+
+fn ssh_config_blocks(
+    snow_configs: &BTreeMap<String, SnowConfig>,
+    host_keys: &BTreeMap<String, String>,
+    known_hosts: &str,
+) -> String {
+    let mut config = String::from("# Generated by `snow ssh-config`. Do not edit.\n");
+    for (host, snow_config) in snow_configs {
+        let Some(address) = host_address(snow_config) else {
+            continue;
+        };
+        let user = snow_config
+            .target_host
+            .as_deref()
+            .and_then(|target_host| split_destination(target_host).0)
+            .or(snow_config.ssh_user.as_deref());
+
+        config += &format!("\nHost {host}\n  HostName {address}\n");
+        if let Some(port) = snow_config.target_port {
+            config += &format!("  Port {port}\n");
+        }
+        if let Some(user) = user {
+            config += &format!("  User {user}\n");
+        }
+        if let Some(ref jump_host) = snow_config.ssh_jump_host {
+            config += &format!("  ProxyJump {jump_host}\n");
+        }
+        if let Some(ref identity_file) = snow_config.ssh_identity_file {
+            config += &format!("  IdentityFile {identity_file}\n");
+        }
+        for option in &snow_config.ssh_options {
+            config += &format!("  {option}\n");
+        }
+        if host_keys.contains_key(host) {
+            config += &format!("  UserKnownHostsFile {known_hosts} ~/.ssh/known_hosts\n");
+        }
+    }
+    config
+}
+
+/// known_hosts lines for the hosts with a key in the repository. Besides their addresses, the
+/// bare host name is always listed, as ssh looks up a `HostKeyAlias` without the port.
+fn known_hosts_lines(
+    snow_configs: &BTreeMap<String, SnowConfig>,
+    host_keys: &BTreeMap<String, String>,
+) -> String {
+    let mut lines = String::new();
+    for (host, key) in host_keys {
+        let mut addresses = vec![];
+        let port = match snow_configs.get(host) {
+            Some(snow_config) => {
+                addresses.extend(host_address(snow_config));
+                addresses.extend(snow_config.vm.as_ref().and_then(|vm| vm.ip.as_deref()));
+                snow_config.target_port.filter(|port| *port != 22)
+            }
+            None => None,
+        };
+        addresses.retain(|address| *address != host);
+        addresses.dedup();
+
+        let patterns = std::iter::once(host.clone())
+            .chain(addresses.iter().map(|address| match port {
+                Some(port) => format!("[{address}]:{port}"),
+                None => address.to_string(),
+            }))
+            .chain(port.map(|port| format!("[{host}]:{port}")))
+            .collect::<Vec<_>>()
+            .join(",");
+        lines += &format!("{patterns} {key}\n");
+    }
+    lines
+}
+
+fn write_file(path: &PathBuf, content: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+pub(crate) fn ssh_config(output: &Option<String>) -> Result<()> {
+    let output = output
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(snow_ssh_config_path);
+    let config = ssh_config_blocks(
+        &SnowConfig::get_all_snow_configs()?,
+        &repo_host_keys(),
+        &snow_known_hosts_path().to_string_lossy(),
+    );
+    write_file(&output, &config)?;
+    log::info!("Wrote ssh config to {}", output.display());
+    log::info!(
+        "Add \"Include {}\" to the top of your ~/.ssh/config to use it.",
+        output.display()
+    );
+    Ok(())
+}
+
+pub(crate) fn known_hosts(output: &Option<String>) -> Result<()> {
+    let output = output
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(snow_known_hosts_path);
+    let lines = known_hosts_lines(&SnowConfig::get_all_snow_configs()?, &repo_host_keys());
+    write_file(&output, &lines)?;
+    log::info!("Wrote known hosts to {}", output.display());
+    Ok(())
+}
+
+#[test]
+fn test_ssh_config_and_known_hosts() {
+    let snow_configs = BTreeMap::from([(
+        "web".to_string(),
+        SnowConfig {
+            target_host: Some("admin@web.example.org".to_string()),
+            target_port: Some(2222),
+            ssh_jump_host: Some("bastion".to_string()),
+            ..Default::default()
+        },
+    )]);
+    let host_keys = BTreeMap::from([("web".to_string(), "ssh-ed25519 AAAAC3Nz".to_string())]);
+
+    assert_eq!(
+        ssh_config_blocks(&snow_configs, &host_keys, "/known_hosts"),
+        "# Generated by `snow ssh-config`. Do not edit.\n\nHost web\n  HostName web.example.org\n  Port 2222\n  User admin\n  ProxyJump bastion\n  UserKnownHostsFile /known_hosts ~/.ssh/known_hosts\n"
+    );
+    assert_eq!(
+        known_hosts_lines(&snow_configs, &host_keys),
+        "web,[web.example.org]:2222,[web]:2222 ssh-ed25519 AAAAC3Nz\n"
+    );
+}
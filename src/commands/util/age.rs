@@ -0,0 +1,173 @@
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use sha2::{Digest, Sha256};
+
+use crate::SnowError;
+use crate::util::Result;
+
+/// The public key agenix-rekey encrypts for if a host has no `age.rekey.hostPubkey`.
+pub(crate) const DUMMY_PUBKEY: &str =
+    "age1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs3290gq";
+
+/// A recipient stanza from the header of an age file, e.g. `-> ssh-ed25519 <tag> <share>`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Stanza {
+    pub(crate) kind: String,
+    pub(crate) args: Vec<String>,
+}
+
+/// Parse the recipient stanzas of an age header. Stanza bodies are skipped, as are the grease
+/// stanzas age adds to keep clients from relying on a fixed set of types.
+fn parse_header(header: &str) -> Option<Vec<Stanza>> {
+    let mut lines = header.lines();
+    if lines.next()?.trim_end() != "age-encryption.org/v1" {
+        return None;
+    }
+    let mut stanzas = vec![];
+    for line in lines {
+        if line.starts_with("---") {
+            return Some(stanzas);
+        }
+        let Some(stanza) = line.strip_prefix("-> ") else {
+            continue;
+        };
+        let mut fields = stanza.split_whitespace().map(|x| x.to_string());
+        let kind = fields.next()?;
+        if !kind.ends_with("-grease") {
+            stanzas.push(Stanza {
+                kind,
+                args: fields.collect(),
+            });
+        }
+    }
+    None
+}
+
+/// Read the recipient stanzas of an age file, which may be ASCII-armored.
+pub(crate) fn read_stanzas(path: &Path) -> Result<Vec<Stanza>> {
+    let mut content = std::fs::read(path)?;
+    if content.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----") {
+        let armored = String::from_utf8_lossy(&content)
+            .lines()
+            .filter(|x| !x.starts_with("-----"))
+            .collect::<String>();
+        content = STANDARD
+            .decode(armored.trim())
+            .map_err(|e| SnowError::Env(format!("{} is not valid armor: {e}", path.display())))?;
+    }
+    let header_end = content
+        .windows(4)
+        .position(|x| x == b"\n---")
+        .and_then(|start| {
+            content[start + 1..]
+                .iter()
+                .position(|&x| x == b'\n')
+                .map(|end| start + 1 + end)
+        })
+        .unwrap_or(content.len());
+    parse_header(&String::from_utf8_lossy(&content[..header_end]))
+        .ok_or_else(|| SnowError::Env(format!("{} is not an age encrypted file", path.display())))
+}
+
+/// The tag ssh stanzas carry to identify their recipient: the first four bytes of the SHA-256
+/// of the key in wire format.
+fn ssh_tag(pubkey: &str) -> Option<String> {
+    let key = STANDARD.decode(pubkey.split_whitespace().nth(1)?).ok()?;
+    Some(STANDARD_NO_PAD.encode(&Sha256::digest(&key)[..4]))
+}
+
+/// Whether `pubkey` is a native X25519 recipient rather than that of a plugin, whose
+/// human-readable part (e.g. `age1yubikey`) comes before the last `1`.
+fn is_x25519(pubkey: &str) -> bool {
+    pubkey.starts_with("age1") && pubkey.rfind('1') == Some(3)
+}
+
+/// How the recipients a file is encrypted for differ from the `expected` (label, public key)
+/// pairs. X25519 and plugin stanzas do not reveal their recipient, so only their number is
+/// compared; ssh stanzas are matched exactly.
+pub(crate) fn recipient_problems(expected: &[(String, String)], stanzas: &[Stanza]) -> Vec<String> {
+    let mut problems = vec![];
+    let mut ssh_tags: Vec<&str> = stanzas
+        .iter()
+        .filter(|x| x.kind.starts_with("ssh-"))
+        .filter_map(|x| x.args.first().map(|x| x.as_str()))
+        .collect();
+    let (mut x25519, mut plugins) = (0, 0);
+
+    for (label, pubkey) in expected {
+        if pubkey.starts_with("ssh-") {
+            match ssh_tag(pubkey).and_then(|tag| ssh_tags.iter().position(|x| *x == tag)) {
+                Some(i) => {
+                    ssh_tags.remove(i);
+                }
+                None => problems.push(format!("not encrypted for {label}")),
+            }
+        } else if is_x25519(pubkey) {
+            x25519 += 1;
+        } else {
+            plugins += 1;
+        }
+    }
+    for tag in ssh_tags {
+        problems.push(format!("encrypted for an unknown ssh key (tag {tag})"));
+    }
+
+    let count = |f: &dyn Fn(&Stanza) -> bool| stanzas.iter().filter(|x| f(x)).count();
+    let found_x25519 = count(&|x| x.kind == "X25519");
+    if found_x25519 != x25519 {
+        problems.push(format!(
+            "encrypted for {found_x25519} X25519 recipients instead of {x25519}"
+        ));
+    }
+    let found_plugins = count(&|x| x.kind != "X25519" && !x.kind.starts_with("ssh-"));
+    if found_plugins != plugins {
+        problems.push(format!(
+            "encrypted for {found_plugins} plugin recipients instead of {plugins}"
+        ));
+    }
+    problems
+}
+
+#[test]
+fn test_recipient_problems() {
+    let stanzas = parse_header(
+        "age-encryption.org/v1\n-> ssh-ed25519 ZkAslA c2hhcmU\nYm9keQ\n-> X25519 c2hhcmU\nYm9keQ\n-> 0-grease x\n\n--- bWFj\n",
+    )
+    .unwrap();
+    assert_eq!(
+        stanzas,
+        vec![
+            Stanza {
+                kind: "ssh-ed25519".to_string(),
+                args: vec!["ZkAslA".to_string(), "c2hhcmU".to_string()]
+            },
+            Stanza {
+                kind: "X25519".to_string(),
+                args: vec!["c2hhcmU".to_string()]
+            }
+        ]
+    );
+    assert_eq!(parse_header("age-encryption.org/v1\n-> X25519 a\n"), None);
+
+    let host = (
+        "web".to_string(),
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f web"
+            .to_string(),
+    );
+    let master = ("master.age".to_string(), DUMMY_PUBKEY.to_string());
+    let yubikey = ("yubikey".to_string(), "age1yubikey1qxyz".to_string());
+    assert!(recipient_problems(&[host.clone(), master.clone()], &stanzas).is_empty());
+    assert_eq!(
+        recipient_problems(&[master], &stanzas),
+        vec!["encrypted for an unknown ssh key (tag ZkAslA)"]
+    );
+    assert_eq!(
+        recipient_problems(&[host, yubikey], &stanzas),
+        vec![
+            "encrypted for 1 X25519 recipients instead of 0",
+            "encrypted for 0 plugin recipients instead of 1"
+        ]
+    );
+}
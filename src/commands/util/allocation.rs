@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use super::{
+    FlakeConfig, Pool, SnowConfig, VmBackend, VmConfig, VmConfigResolved, hypervisor, repo_path,
+};
+use crate::SnowError;
+use crate::util::Result;
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq)]
+pub(crate) struct Allocation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) ip: Option<String>,
+}
+
+/// Where the snow module reads allocations from, i.e. `snow.vm.allocationFile` mapped back to
+/// the repository, along with the key of the host in it.
+fn allocation_target(vm: &VmConfig, vm_configuration: &str) -> Result<(PathBuf, String)> {
+    let (Some(file), Some(name)) = (&vm.allocation_file, &vm.allocation_name) else {
+        return Err(SnowError::SnowConfig(format!(
+            "snow.vm.allocationFile is not set for host \"{vm_configuration}\", so an allocation could not be recorded"
+        )));
+    };
+    Ok((repo_path(file), name.clone()))
+}
+
+fn read_allocations(path: &Path) -> Result<BTreeMap<String, Allocation>> {
+    match std::fs::read_to_string(path) {
+        Ok(raw) => Ok(serde_json::from_str(&raw)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_allocations(path: &Path, allocations: &BTreeMap<String, Allocation>) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(allocations)? + "\n")?;
+    Ok(())
+}
+
+fn next_free_id(pool: &Pool<usize>, used: &BTreeSet<usize>) -> Option<usize> {
+    (pool.first..=pool.last).find(|id| !used.contains(id))
+}
+
+fn next_free_ip(pool: &Pool<Ipv4Addr>, used: &BTreeSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+    (u32::from(pool.first)..=u32::from(pool.last))
+        .map(Ipv4Addr::from)
+        .find(|ip| !used.contains(ip))
+}
+
+/// Pick an id and address for the VM from the pools declared in the flake, if its snow config
+/// leaves them out. Returns whether anything was allocated; the choice is recorded in
+/// `snow.vm.allocationFile`, which has to be staged for the flake to see it.
+pub(crate) fn allocate_vm(vm_configuration: &str) -> Result<bool> {
+    let snow_config = SnowConfig::get_snow_config(vm_configuration)?;
+    let Some(vm) = snow_config.vm else {
+        return Err(SnowError::SnowConfig(format!(
+            "VM settings are not configured for host \"{vm_configuration}\""
+        )));
+    };
+    // libvirt knows domains by name, only Proxmox needs an id
+    let needs_id = vm.id.is_none() && !matches!(vm.backend, VmBackend::Libvirt);
+    if !needs_id && vm.ip.is_some() {
+        return Ok(false);
+    }
+
+    let (file, name) = allocation_target(&vm, vm_configuration)?;
+    let pools = FlakeConfig::get_flake_config()?.vm_pools;
+    let mut allocations = read_allocations(&file)?;
+    let mut used_ids = BTreeSet::new();
+    let mut used_ips = BTreeSet::new();
+    for (host, snow_config) in SnowConfig::get_all_snow_configs()? {
+        if host == vm_configuration {
+            continue;
+        }
+        if let Some(other) = snow_config.vm {
+            used_ids.extend(other.id);
+            used_ips.extend(other.ip.and_then(|ip| ip.parse::<Ipv4Addr>().ok()));
+        }
+    }
+    for (key, allocation) in &allocations {
+        if *key != name {
+            used_ids.extend(allocation.id);
+            used_ips.extend(
+                allocation
+                    .ip
+                    .as_ref()
+                    .and_then(|ip| ip.parse::<Ipv4Addr>().ok()),
+            );
+        }
+    }
+
+    let mut allocation = Allocation::default();
+    if needs_id {
+        // Ask the hypervisor about VMs snow does not manage. Its config needs some id to
+        // resolve, which is not used for listing.
+        let mut placeholder = vm.clone();
+        placeholder.id.get_or_insert(0);
+        let placeholder: VmConfigResolved = placeholder.try_into()?;
+        used_ids.extend(hypervisor(vm_configuration, &placeholder)?.vm_ids()?);
+
+        let pool = pools.ids.as_ref().ok_or_else(|| {
+            SnowError::SnowConfig(
+                "snow.vm.id is not set, and no vmPools.ids are declared".to_string(),
+            )
+        })?;
+        allocation.id =
+            Some(next_free_id(pool, &used_ids).ok_or_else(|| {
+                SnowError::SnowConfig("no VM id in vmPools.ids is free".to_string())
+            })?);
+    }
+    if vm.ip.is_none() {
+        let pool = pools.ips.as_ref().ok_or_else(|| {
+            SnowError::SnowConfig(
+                "snow.vm.ip is not set, and no vmPools.ips are declared".to_string(),
+            )
+        })?;
+        allocation.ip = Some(
+            next_free_ip(pool, &used_ips)
+                .ok_or_else(|| {
+                    SnowError::SnowConfig("no address in vmPools.ips is free".to_string())
+                })?
+                .to_string(),
+        );
+    }
+
+    log::info!(
+        "Allocated {} for {vm_configuration}",
+        [
+            allocation.id.map(|id| format!("id {id}")),
+            allocation.ip.as_ref().map(|ip| format!("address {ip}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" and ")
+    );
+    allocations.insert(name, allocation);
+    write_allocations(&file, &allocations)?;
+    Ok(true)
+}
+
+#[test]
+fn test_next_free() {
+    let used = BTreeSet::from([100, 101, 103]);
+    assert_eq!(
+        next_free_id(
+            &Pool {
+                first: 100,
+                last: 110
+            },
+            &used
+        ),
+        Some(102)
+    );
+    assert_eq!(
+        next_free_id(
+            &Pool {
+                first: 100,
+                last: 101
+            },
+            &used
+        ),
+        None
+    );
+
+    let used = BTreeSet::from([Ipv4Addr::new(10, 0, 0, 254), Ipv4Addr::new(10, 0, 0, 255)]);
+    assert_eq!(
+        next_free_ip(
+            &Pool {
+                first: Ipv4Addr::new(10, 0, 0, 254),
+                last: Ipv4Addr::new(10, 0, 1, 10)
+            },
+            &used
+        ),
+        Some(Ipv4Addr::new(10, 0, 1, 0))
+    );
+}
+
+#[test]
+fn test_allocations_round_trip() {
+    let vm = VmConfig {
+        allocation_file: Some("/nix/store/abc123-source/vms/allocations.json".to_string()),
+        allocation_name: Some("web".to_string()),
+        ..Default::default()
+    };
+    let (file, name) = allocation_target(&vm, "web").unwrap();
+    assert_eq!(file, PathBuf::from("vms/allocations.json"));
+    assert!(allocation_target(&VmConfig::default(), "web").is_err());
+
+    let dir = std::env::temp_dir().join(format!("snow-allocations-{}", std::process::id()));
+    let file = dir.join(file);
+    let allocations = BTreeMap::from([(
+        name,
+        Allocation {
+            id: Some(120),
+            ip: Some("10.0.0.20".to_string()),
+        },
+    )]);
+    write_allocations(&file, &allocations).unwrap();
+    // The shape the snow module reads through `allocations.${allocationName}.id`
+    let raw: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+    assert_eq!(raw["web"]["id"], 120);
+    assert_eq!(raw["web"]["ip"], "10.0.0.20");
+    assert_eq!(read_allocations(&file).unwrap(), allocations);
+    std::fs::remove_dir_all(dir).unwrap();
+}
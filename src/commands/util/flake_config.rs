@@ -0,0 +1,87 @@
+use crate::SnowError;
+use crate::util::Result;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+use super::read_from_repl;
+
+/// Flake-wide settings, read from the `snow` output of the flake.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FlakeConfig {
+    #[serde(default)]
+    pub(crate) commands: BTreeMap<String, CustomCommand>,
+    #[serde(default)]
+    pub(crate) vm_pools: VmPools,
+    #[serde(default)]
+    pub(crate) new_host: NewHost,
+    /// Where public host keys are stored in the repository, with `@name@` standing for the
+    /// host, e.g. `keys/@name@.pub`.
+    pub(crate) host_key_path: Option<String>,
+}
+
+/// How `snow new host` scaffolds a host: the file used as `hosts/<name>/default.nix`, and the
+/// entry added to `nixosConfigurations` in flake.nix, which defaults to a copy of an existing
+/// one. Both may contain the placeholders `@name@`, `@targetHost@` and `@sshUser@`.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NewHost {
+    pub(crate) template: Option<String>,
+    pub(crate) entry: Option<String>,
+}
+
+/// Ranges VM ids and addresses are allocated from if a host does not set them.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VmPools {
+    pub(crate) ids: Option<Pool<usize>>,
+    pub(crate) ips: Option<Pool<Ipv4Addr>>,
+}
+
+/// An inclusive range.
+#[derive(Deserialize)]
+pub(crate) struct Pool<T> {
+    pub(crate) first: T,
+    pub(crate) last: T,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CustomCommand {
+    pub(crate) command: String,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+    #[serde(default)]
+    pub(crate) args: Vec<String>,
+    #[serde(default)]
+    pub(crate) sudo: bool,
+    #[serde(default)]
+    pub(crate) output: CommandOutput,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CommandOutput {
+    Progress,
+    Silent,
+    #[default]
+    Verbose,
+}
+
+impl FlakeConfig {
+    pub(crate) fn get_flake_config() -> Result<Self> {
+        // The leading dot makes nix take the attribute path as is. Otherwise, it would look for
+        // `packages.<system>.snow` and `legacyPackages.<system>.snow` first.
+        match read_from_repl(".snow", vec!["--json"]) {
+            Ok(flake_config_raw) => Ok(serde_json::from_str(&flake_config_raw)?),
+            Err(SnowError::Nix(e)) if e.contains("does not provide attribute") => {
+                log::debug!("flake has no snow output, using defaults");
+                Ok(Self::default())
+            }
+            Err(e) => Err(SnowError::Nix(format!(
+                "could not read snow config of the flake: {e}"
+            ))),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::util::Result;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::BTreeMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::read_from_repl;
+
+// Evaluating the flake easily takes several seconds, which is far too slow for tab completion.
+// Results are therefore cached per flake, and invalidated whenever the flake, its lock file, the
+// git index or anything under hosts/ change.
+#[derive(Deserialize, Serialize, Default)]
+struct FlakeInfoCache {
+    fingerprint: String,
+    values: BTreeMap<String, serde_json::Value>,
+}
+
+fn cache_path() -> Option<PathBuf> {
+    let cache_dir = std::env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok()?;
+    let mut hasher = DefaultHasher::new();
+    std::env::current_dir().ok()?.hash(&mut hasher);
+    Some(
+        cache_dir
+            .join("snow")
+            .join(format!("flake-info-{:016x}.json", hasher.finish())),
+    )
+}
+
+fn modified(file: impl AsRef<Path>) -> String {
+    std::fs::metadata(file)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos().to_string())
+        .unwrap_or_default()
+}
+
+/// Hash of the modification times of everything under `hosts/`. Hosts and their tags are mostly
+/// defined there rather than in flake.nix, and the flake sees edits to them before they are staged.
+fn hosts_fingerprint() -> String {
+    fn visit(dir: &Path, hasher: &mut DefaultHasher) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+        entries.sort_by_key(|entry| entry.path());
+        for entry in entries {
+            let path = entry.path();
+            path.hash(hasher);
+            modified(&path).hash(hasher);
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                visit(&path, hasher);
+            }
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    visit(Path::new("hosts"), &mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn fingerprint() -> String {
+    let mut parts: Vec<String> = ["flake.nix", "flake.lock", ".git/index"]
+        .iter()
+        .map(modified)
+        .collect();
+    parts.push(hosts_fingerprint());
+    parts.join(":")
+}
+
+fn read_cache() -> FlakeInfoCache {
+    let fingerprint = fingerprint();
+    cache_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|raw| serde_json::from_str::<FlakeInfoCache>(&raw).ok())
+        .filter(|cache| cache.fingerprint == fingerprint)
+        .unwrap_or(FlakeInfoCache {
+            fingerprint,
+            values: BTreeMap::new(),
+        })
+}
+
+fn write_cache(cache: &FlakeInfoCache) {
+    let Some(path) = cache_path() else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    if let Ok(raw) = serde_json::to_string(cache) {
+        let _ = std::fs::write(path, raw);
+    }
+}
+
+/// Evaluate `attr` of the current flake with `apply` applied to it. If `cached` is set, a
+/// previous result is reused as long as the flake has not changed since.
+fn eval_flake<T: DeserializeOwned>(attr: &str, apply: &str, cached: bool) -> Result<T> {
+    let key = format!("{attr}|{apply}");
+    let mut cache = read_cache();
+    if cached && let Some(value) = cache.values.get(&key) {
+        return Ok(serde_json::from_value(value.clone())?);
+    }
+
+    let raw = read_from_repl(attr, vec!["--json", "--apply", apply])?;
+    let value: serde_json::Value = serde_json::from_str(&raw)?;
+    cache.values.insert(key, value.clone());
+    write_cache(&cache);
+    Ok(serde_json::from_value(value)?)
+}
+
+pub(crate) fn nixos_configuration_names(cached: bool) -> Result<Vec<String>> {
+    eval_flake("nixosConfigurations", "builtins.attrNames", cached)
+}
+
+pub(crate) fn home_configuration_names(cached: bool) -> Result<Vec<String>> {
+    eval_flake("homeConfigurations", "builtins.attrNames", cached)
+}
+
+pub(crate) fn dev_shell_names(cached: bool) -> Result<Vec<String>> {
+    let mut names: Vec<String> = eval_flake(
+        "devShells",
+        "shells: builtins.concatMap builtins.attrNames (builtins.attrValues shells)",
+        cached,
+    )?;
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Tags of every nixosConfiguration, keyed by host.
+pub(crate) fn host_tags(cached: bool) -> Result<BTreeMap<String, Vec<String>>> {
+    eval_flake(
+        "nixosConfigurations",
+        "hosts: builtins.mapAttrs (_: host: host.config.snow.tags or [ ]) hosts",
+        cached,
+    )
+}
+
+/// All nixosConfigurations carrying the given tag.
+pub(crate) fn hosts_with_tag(tag: &str) -> Result<Vec<String>> {
+    Ok(host_tags(false)?
+        .into_iter()
+        .filter(|(_, tags)| tags.iter().any(|t| t == tag))
+        .map(|(host, _)| host)
+        .collect())
+}
+
+/// Paths of all age-encrypted files in the flake, relative to its root. Hidden directories (such
+/// as the storage for rekeyed secrets) are skipped.
+pub(crate) fn secret_files() -> Vec<String> {
+    fn walk(dir: &std::path::Path, found: &mut Vec<String>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name == "result" {
+                continue;
+            }
+            if path.is_dir() {
+                walk(&path, found);
+            } else if name.ends_with(".age") {
+                found.push(
+                    path.strip_prefix(".")
+                        .unwrap_or(&path)
+                        .to_string_lossy()
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    let mut found = vec![];
+    walk(std::path::Path::new("."), &mut found);
+    found.sort();
+    found
+}
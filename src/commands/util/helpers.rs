@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use crate::{commands::runners::SnowCommand, util::Result};
 
 pub(crate) fn wrap(arg: &str, with_submodules: bool) -> String {
@@ -14,3 +16,23 @@ pub(crate) fn read_from_repl(attr: &str, extra_args: Vec<&str>) -> Result<String
     let command = SnowCommand::new_nix("nix".to_string(), args, false);
     command.run_with_return()
 }
+
+/// Map a path inside the flake's copy in the nix store back to the repository.
+pub(crate) fn repo_relative(store_path: &str) -> Option<PathBuf> {
+    let (_, source_relative) = store_path.strip_prefix("/nix/store/")?.split_once('/')?;
+    Some(PathBuf::from(source_relative))
+}
+
+/// A path from the evaluated flake, relative to the repository if it lies within the flake.
+pub(crate) fn repo_path(path: &str) -> PathBuf {
+    repo_relative(path).unwrap_or_else(|| PathBuf::from(path))
+}
+
+#[test]
+fn test_repo_relative() {
+    assert_eq!(
+        repo_relative("/nix/store/abc123-source/secrets/rekeyed/web"),
+        Some(PathBuf::from("secrets/rekeyed/web"))
+    );
+    assert_eq!(repo_relative("/home/user/flake/secrets"), None);
+}
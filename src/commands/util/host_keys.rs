@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use super::{FlakeConfig, nixos_configuration_names, read_from_repl, state_dir};
+use crate::SnowError;
+use crate::commands::runners::SnowCommand;
+use crate::util::Result;
+
+static HOST_KEY_PATH: OnceLock<Option<String>> = OnceLock::new();
+
+/// The `hostKeyPath` template of the flake, read once per run.
+fn host_key_template() -> Result<Option<&'static str>> {
+    if let Some(template) = HOST_KEY_PATH.get() {
+        return Ok(template.as_deref());
+    }
+    let template = FlakeConfig::get_flake_config()?.host_key_path;
+    Ok(HOST_KEY_PATH.get_or_init(|| template).as_deref())
+}
+
+fn fill_host_key_template(template: &str, host: &str) -> PathBuf {
+    PathBuf::from(template.replace("@name@", host))
+}
+
+/// Public host keys stored in the repository, keyed by host. Keys are read from
+/// `hosts/<host>/ssh_host_ed25519_key.pub`, `vms/keys/ssh_host_<host>_ed25519_key.pub`, and the
+/// `hostKeyPath` of the flake if it sets one.
+pub(crate) fn repo_host_keys() -> BTreeMap<String, String> {
+    let mut keys = BTreeMap::new();
+
+    if let Ok(Some(template)) = host_key_template() {
+        for host in nixos_configuration_names(true).unwrap_or_default() {
+            if let Some(key) = read_public_key(&fill_host_key_template(template, &host)) {
+                keys.insert(host, key);
+            }
+        }
+    }
+
+    if let Ok(entries) = std::fs::read_dir("hosts") {
+        for entry in entries.flatten() {
+            let path = entry.path().join("ssh_host_ed25519_key.pub");
+            if let Some(key) = read_public_key(&path) {
+                keys.entry(entry.file_name().to_string_lossy().to_string())
+                    .or_insert(key);
+            }
+        }
+    }
+
+    if let Ok(entries) = std::fs::read_dir("vms/keys") {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(host) = file_name.strip_suffix(".pub") else {
+                continue;
+            };
+            let host = host.strip_prefix("ssh_host_").unwrap_or(host);
+            let host = host.strip_suffix("_ed25519_key").unwrap_or(host);
+            if let Some(key) = read_public_key(&entry.path()) {
+                keys.entry(host.to_string()).or_insert(key);
+            }
+        }
+    }
+
+    keys
+}
+
+/// Read an OpenSSH public key file, dropping the trailing comment.
+pub(crate) fn read_public_key(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let mut fields = content.split_whitespace();
+    Some(format!("{} {}", fields.next()?, fields.next()?))
+}
+
+/// Where the public host key of a host is stored: the `hostKeyPath` of the flake, or
+/// `hosts/<host>/ssh_host_ed25519_key.pub`.
+pub(crate) fn host_key_path(host: &str) -> Result<PathBuf> {
+    Ok(match host_key_template()? {
+        Some(template) => fill_host_key_template(template, host),
+        None => PathBuf::from(format!("hosts/{host}/ssh_host_ed25519_key.pub")),
+    })
+}
+
+/// Where the public host key of a VM is stored: the `hostKeyPath` of the flake, or
+/// `vms/keys/ssh_host_<vm>_ed25519_key.pub`.
+pub(crate) fn vm_host_key_path(vm_configuration: &str) -> Result<PathBuf> {
+    Ok(match host_key_template()? {
+        Some(template) => fill_host_key_template(template, vm_configuration),
+        None => PathBuf::from(format!(
+            "vms/keys/ssh_host_{vm_configuration}_ed25519_key.pub"
+        )),
+    })
+}
+
+/// Store a public host key, creating the directory it goes into.
+pub(crate) fn write_host_key(path: &Path, key: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, key.trim())?;
+    log::info!("Wrote host pubkey to {}", path.to_string_lossy());
+    Ok(())
+}
+
+/// Make sure agenix-rekey encrypts for the key stored at `path`, as it would otherwise rekey
+/// secrets for a different key. Hosts not using agenix-rekey are skipped. The key has to be
+/// staged for the flake to see it.
+pub(crate) fn check_rekey_host_key(host: &str, path: &Path) -> Result<()> {
+    let configured = match read_from_repl(
+        &format!("nixosConfigurations.{host}.config.age.rekey.hostPubkey"),
+        vec![
+            "--raw",
+            "--apply",
+            "key: if builtins.isPath key then builtins.readFile key else key",
+        ],
+    ) {
+        Ok(configured) => configured,
+        Err(e) => {
+            log::debug!("not checking age.rekey.hostPubkey of {host}: {e}");
+            return Ok(());
+        }
+    };
+    if !same_public_key(&configured, &std::fs::read_to_string(path)?) {
+        return Err(SnowError::SnowConfig(format!(
+            "age.rekey.hostPubkey of {host} is not the key stored at {}, point it to that file",
+            path.to_string_lossy()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether two OpenSSH public keys are the same, ignoring their comments.
+pub(crate) fn same_public_key(a: &str, b: &str) -> bool {
+    let fields = |key: &str| key.split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+    !fields(a).is_empty() && fields(a) == fields(b)
+}
+
+/// Where a locally generated private host key is kept until it has been put on the host.
+pub(crate) fn host_key_state_path(host: &str) -> Result<PathBuf> {
+    Ok(state_dir("host-keys")?.join(format!("ssh_host_{host}_ed25519_key")))
+}
+
+/// Generate an ed25519 host key pair at `key` and `key.pub`, replacing any existing one.
+pub(crate) fn generate_host_key(key: &Path, comment: &str) -> Result<()> {
+    let public_key = PathBuf::from(format!("{}.pub", key.display()));
+    for file in [key, &public_key] {
+        if let Err(e) = std::fs::remove_file(file)
+            && e.kind() != ErrorKind::NotFound
+        {
+            return Err(e.into());
+        }
+    }
+    SnowCommand::new(
+        "ssh-keygen".to_string(),
+        vec![
+            "-q",
+            "-t",
+            "ed25519",
+            "-N",
+            "",
+            "-C",
+            comment,
+            "-f",
+            &key.to_string_lossy(),
+        ],
+        false,
+    )
+    .run_silent()
+}
+
+#[test]
+fn test_host_key_paths() {
+    assert_eq!(
+        fill_host_key_template("keys/@name@.pub", "web"),
+        PathBuf::from("keys/web.pub")
+    );
+    assert!(same_public_key(
+        "ssh-ed25519 AAAAC3 web\n",
+        "ssh-ed25519 AAAAC3 root@web"
+    ));
+    assert!(!same_public_key("ssh-ed25519 AAAAC3", "ssh-ed25519 AAAAC4"));
+    assert!(!same_public_key("", ""));
+}
@@ -0,0 +1,608 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::Value;
+use ureq::http::Response;
+use ureq::tls::TlsConfig;
+use ureq::{Agent, Body, SendBody};
+
+use super::{Hypervisor, ProxmoxGuest, VmStatus};
+use crate::SnowError;
+use crate::commands::runners::SnowCommand;
+use crate::commands::util::{Progress, ProxmoxApiResolved, VmKind};
+use crate::util::Result;
+
+/// Drives Proxmox through the Proxmox VE HTTP API, authenticating with an API token.
+pub(crate) struct ProxmoxApi<'a> {
+    agent: Agent,
+    config: &'a ProxmoxApiResolved,
+    id: usize,
+    guest: ProxmoxGuest,
+}
+
+/// The percentage of a task log line like `progress 45% (read 1234 of 5678 bytes)`.
+fn task_progress(line: &str) -> Option<usize> {
+    let (percent, _) = line.strip_prefix("progress ")?.split_once('%')?;
+    percent.trim().parse().ok()
+}
+
+/// Percent-encode a single path segment, e.g. a volume id like `local:backup/image.vma.zst`.
+fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+impl<'a> ProxmoxApi<'a> {
+    pub(crate) fn new(config: &'a ProxmoxApiResolved, id: usize, guest: ProxmoxGuest) -> Self {
+        let agent = Agent::config_builder()
+            .tls_config(
+                TlsConfig::builder()
+                    .disable_verification(config.insecure)
+                    .build(),
+            )
+            .http_status_as_error(false)
+            .build()
+            .into();
+        Self {
+            agent,
+            config,
+            id,
+            guest,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/api2/json{path}", self.config.url)
+    }
+
+    /// Path of a guest, e.g. `/nodes/pve/qemu/100`.
+    fn guest_path(&self, id: usize) -> String {
+        format!("/nodes/{}/{}/{id}", self.config.node, self.guest.api_type())
+    }
+
+    fn authorization(&self) -> String {
+        format!(
+            "PVEAPIToken={}={}",
+            self.config.token_id, self.config.token_secret
+        )
+    }
+
+    /// Unwrap the `data` field of an API response, turning non-2xx responses into errors.
+    fn data(response: Response<Body>) -> Result<Value> {
+        let status = response.status();
+        let body = response.into_body().read_to_string()?;
+        if !status.is_success() {
+            return Err(SnowError::Proxmox(format!("{status}: {}", body.trim())));
+        }
+        let mut value: Value = serde_json::from_str(&body)?;
+        Ok(value["data"].take())
+    }
+
+    fn get(&self, path: &str) -> Result<Value> {
+        log::debug!("GET {path}");
+        Self::data(
+            self.agent
+                .get(self.url(path))
+                .header("Authorization", self.authorization())
+                .call()?,
+        )
+    }
+
+    fn post(&self, path: &str, form: &[(&str, &str)]) -> Result<Value> {
+        log::debug!("POST {path}");
+        Self::data(
+            self.agent
+                .post(self.url(path))
+                .header("Authorization", self.authorization())
+                .send_form(form.iter().copied())?,
+        )
+    }
+
+    fn put(&self, path: &str, form: &[(&str, &str)]) -> Result<Value> {
+        log::debug!("PUT {path}");
+        Self::data(
+            self.agent
+                .put(self.url(path))
+                .header("Authorization", self.authorization())
+                .send_form(form.iter().copied())?,
+        )
+    }
+
+    fn delete(&self, path: &str) -> Result<Value> {
+        log::debug!("DELETE {path}");
+        Self::data(
+            self.agent
+                .delete(self.url(path))
+                .header("Authorization", self.authorization())
+                .call()?,
+        )
+    }
+
+    /// Wait for the task started by a request to finish. Most endpoints return the task's UPID,
+    /// some return nothing if they complete synchronously. If `name` is given, a progress bar
+    /// fed by the task's `progress N%` log lines is shown.
+    fn wait_for_task(&self, upid: Value, name: Option<&str>) -> Result<()> {
+        let Some(upid) = upid.as_str() else {
+            return Ok(());
+        };
+        let task_path = format!("/nodes/{}/tasks/{}", self.config.node, encode_segment(upid));
+
+        let mut progress = match name {
+            Some(name) => Some(Progress::new(name, 99)?),
+            None => None,
+        };
+        let mut log_lines = 0;
+        let mut percent_done = 0;
+        let status = loop {
+            let status = self.get(&format!("{task_path}/status"))?;
+
+            if let Some(ref mut progress) = progress {
+                let log = self.get(&format!("{task_path}/log?start={log_lines}&limit=500"))?;
+                for line in log.as_array().into_iter().flatten() {
+                    log_lines += 1;
+                    if let Some(percent) = line["t"].as_str().and_then(task_progress) {
+                        while percent_done < percent {
+                            progress.progress();
+                            percent_done += 1;
+                        }
+                    }
+                }
+            }
+
+            if status["status"].as_str() == Some("stopped") {
+                break status;
+            }
+            for _ in 0..10 {
+                if let Some(ref mut progress) = progress {
+                    progress.refresh()?;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+
+        let success = status["exitstatus"].as_str() == Some("OK");
+        if let Some(ref mut progress) = progress {
+            progress.cleanup(success)?;
+        }
+        match success {
+            true => Ok(()),
+            false => Err(SnowError::Proxmox(format!(
+                "task {upid} failed: {}",
+                status["exitstatus"].as_str().unwrap_or("unknown error")
+            ))),
+        }
+    }
+}
+
+/// Shell command printing the path of a backup volume, creating the directory it goes into.
+fn backup_path_command(volume: &str) -> String {
+    format!("path=$(pvesm path {volume}) && mkdir -p \"$(dirname \"$path\")\" && echo \"$path\"")
+}
+
+impl Hypervisor for ProxmoxApi<'_> {
+    fn image_variant(&self) -> &'static str {
+        self.guest.image_variant()
+    }
+
+    fn image_extension(&self) -> &'static str {
+        self.guest.image_extension()
+    }
+
+    fn vm_name(&self) -> String {
+        format!("VM {}", self.id)
+    }
+
+    fn archive_name(&self) -> String {
+        self.guest.archive_name(self.id)
+    }
+
+    fn kind(&self) -> VmKind {
+        self.guest.kind()
+    }
+
+    fn upload_image(&self, image: &Path, archive_name: &str) -> Result<String> {
+        let volume = format!(
+            "{}:{}/{archive_name}",
+            self.config.storage,
+            self.guest.content()
+        );
+        if matches!(self.guest, ProxmoxGuest::Qemu) {
+            // The API only takes uploads of ISO images and container templates, so backups are
+            // copied to wherever the storage keeps them
+            log::info!("Copying the VM image to the proxmox host...");
+            let path = SnowCommand::new(
+                "ssh".to_string(),
+                vec![&self.config.ssh_host, &backup_path_command(&volume)],
+                false,
+            )
+            .run_with_return()?;
+            SnowCommand::new(
+                "scp".to_string(),
+                vec![
+                    &image.to_string_lossy(),
+                    &format!("{}:{}", self.config.ssh_host, path.trim()),
+                ],
+                false,
+            )
+            .run_verbose()?;
+            return Ok(volume);
+        }
+
+        // Stream the image as multipart/form-data instead of reading it into memory; images are
+        // easily several GiB.
+        let boundary = format!("snow-{:x}", std::process::id());
+        let content = self.guest.content();
+        let head = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"content\"\r\n\r\n{content}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"filename\"; filename=\"{archive_name}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        );
+        let tail = format!("\r\n--{boundary}--\r\n");
+        let file = File::open(image)?;
+        let content_length = head.len() as u64 + file.metadata()?.len() + tail.len() as u64;
+        let body = Cursor::new(head.into_bytes())
+            .chain(file)
+            .chain(Cursor::new(tail.into_bytes()));
+
+        log::info!("Uploading the VM image to the proxmox host...");
+        let path = format!(
+            "/nodes/{}/storage/{}/upload",
+            self.config.node, self.config.storage
+        );
+        log::debug!("POST {path}");
+        let upid = Self::data(
+            self.agent
+                .post(self.url(&path))
+                .header("Authorization", self.authorization())
+                .header(
+                    "Content-Type",
+                    format!("multipart/form-data; boundary={boundary}"),
+                )
+                .header("Content-Length", content_length.to_string())
+                .send(SendBody::from_owned_reader(body))?,
+        )?;
+        self.wait_for_task(upid, None)?;
+        Ok(volume)
+    }
+
+    fn remove_image(&self, archive_name: &str) -> Result<()> {
+        let volume = format!(
+            "{}:{}/{archive_name}",
+            self.config.storage,
+            self.guest.content()
+        );
+        let upid = self.delete(&format!(
+            "/nodes/{}/storage/{}/content/{}",
+            self.config.node,
+            self.config.storage,
+            encode_segment(&volume)
+        ))?;
+        self.wait_for_task(upid, None)
+    }
+
+    fn restore(&self, archive: &str, storage: Option<&str>) -> Result<()> {
+        let id = self.id.to_string();
+        let mut form = vec![("vmid", id.as_str())];
+        match self.guest {
+            ProxmoxGuest::Qemu => form.extend([("archive", archive), ("unique", "1")]),
+            ProxmoxGuest::Lxc(ref settings) => {
+                form.push(("ostemplate", archive));
+                form.extend(
+                    settings
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), value.as_str())),
+                );
+            }
+        }
+        if let Some(storage) = storage {
+            form.push(("storage", storage));
+        }
+        let upid = self.post(
+            &format!("/nodes/{}/{}", self.config.node, self.guest.api_type()),
+            &form,
+        )?;
+        self.wait_for_task(upid, Some("import vm"))
+    }
+
+    fn vm_ids(&self) -> Result<Vec<usize>> {
+        let vms = self.get("/cluster/resources?type=vm")?;
+        Ok(vms
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|vm| Some(vm["vmid"].as_u64()? as usize))
+            .collect())
+    }
+
+    fn clone_template(&self, template_id: usize, name: &str, storage: Option<&str>) -> Result<()> {
+        let id = self.id.to_string();
+        let name_key = match self.guest {
+            ProxmoxGuest::Qemu => "name",
+            ProxmoxGuest::Lxc(_) => "hostname",
+        };
+        let mut form = vec![("newid", id.as_str()), (name_key, name), ("full", "1")];
+        if let Some(storage) = storage {
+            form.push(("storage", storage));
+        }
+        let upid = self.post(&format!("{}/clone", self.guest_path(template_id)), &form)?;
+        self.wait_for_task(upid, None)
+    }
+
+    fn resize_disk(&self, disk: &str, size: &str) -> Result<()> {
+        let upid = self.put(
+            &format!("{}/resize", self.guest_path(self.id)),
+            &[("disk", disk), ("size", size)],
+        )?;
+        self.wait_for_task(upid, None)
+    }
+
+    fn start(&self) -> Result<()> {
+        let upid = self.post(&format!("{}/status/start", self.guest_path(self.id)), &[])?;
+        self.wait_for_task(upid, None)
+    }
+
+    fn reboot(&self) -> Result<()> {
+        let upid = self.post(&format!("{}/status/reboot", self.guest_path(self.id)), &[])?;
+        self.wait_for_task(upid, None)
+    }
+
+    fn stop(&self) -> Result<()> {
+        let upid = self.post(
+            &format!("{}/status/shutdown", self.guest_path(self.id)),
+            &[("forceStop", "1")],
+        )?;
+        self.wait_for_task(upid, None)
+    }
+
+    fn status(&self) -> Result<VmStatus> {
+        let status = self.get(&format!("{}/status/current", self.guest_path(self.id)))?;
+        Ok(serde_json::from_value(status)?)
+    }
+
+    fn exists(&self) -> Result<bool> {
+        Ok(self.vm_ids()?.contains(&self.id))
+    }
+
+    fn config(&self) -> Result<BTreeMap<String, String>> {
+        let config = self.get(&format!("{}/config", self.guest_path(self.id)))?;
+        Ok(config
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (key.clone(), value)
+            })
+            .collect())
+    }
+
+    fn set(&self, settings: &BTreeMap<String, String>) -> Result<()> {
+        let form = settings
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        // Containers are only configured synchronously, through PUT
+        let upid = match self.guest {
+            ProxmoxGuest::Qemu => {
+                self.post(&format!("{}/config", self.guest_path(self.id)), &form)?
+            }
+            ProxmoxGuest::Lxc(_) => {
+                self.put(&format!("{}/config", self.guest_path(self.id)), &form)?
+            }
+        };
+        self.wait_for_task(upid, None)
+    }
+
+    fn destroy(&self) -> Result<()> {
+        let upid = self.delete(&format!(
+            "{}?purge=1&destroy-unreferenced-disks=1",
+            self.guest_path(self.id)
+        ))?;
+        self.wait_for_task(upid, None)
+    }
+}
+
+/// Minimal HTTP server answering every request with the first response whose key is a prefix of
+/// "<METHOD> <path>". Returns the base url, and all requests (request line, headers and body) it
+/// has received.
+#[cfg(test)]
+fn mock_server(
+    responses: Vec<(&'static str, u16, &'static str)>,
+) -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let received = Arc::clone(&requests);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+                request += &line;
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request += &String::from_utf8_lossy(&body);
+
+            let (status, response) = responses
+                .iter()
+                .find(|(key, _, _)| request.starts_with(key))
+                .map(|(_, status, response)| (*status, *response))
+                .unwrap_or((404, "{\"data\":null}"));
+            received.lock().unwrap().push(request);
+            write!(
+                stream,
+                "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                response.len()
+            )
+            .unwrap();
+        }
+    });
+    (url, requests)
+}
+
+#[cfg(test)]
+fn mock_config(url: String) -> ProxmoxApiResolved {
+    ProxmoxApiResolved {
+        url,
+        node: "pve".to_string(),
+        storage: "images".to_string(),
+        token_id: "snow@pve!test".to_string(),
+        token_secret: "secret".to_string(),
+        insecure: false,
+        ssh_host: "root@pve".to_string(),
+    }
+}
+
+#[test]
+fn test_api_start_waits_for_task() {
+    let (url, requests) = mock_server(vec![
+        (
+            "POST /api2/json/nodes/pve/qemu/100/status/start",
+            200,
+            r#"{"data":"UPID:pve:1:start"}"#,
+        ),
+        (
+            "GET /api2/json/nodes/pve/tasks/UPID%3Apve%3A1%3Astart/status",
+            200,
+            r#"{"data":{"status":"stopped","exitstatus":"OK"}}"#,
+        ),
+    ]);
+    let config = mock_config(url);
+    ProxmoxApi::new(&config, 100, ProxmoxGuest::Qemu)
+        .start()
+        .unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(
+        requests[0]
+            .to_lowercase()
+            .contains("authorization: pveapitoken=snow@pve!test=secret")
+    );
+}
+
+#[test]
+fn test_api_errors() {
+    let (url, _) = mock_server(vec![
+        (
+            "POST /api2/json/nodes/pve/qemu/100/status/reboot",
+            200,
+            r#"{"data":"UPID:pve:2:reboot"}"#,
+        ),
+        (
+            "GET /api2/json/nodes/pve/tasks/UPID%3Apve%3A2%3Areboot/status",
+            200,
+            r#"{"data":{"status":"stopped","exitstatus":"VM 100 not running"}}"#,
+        ),
+        (
+            "POST /api2/json/nodes/pve/qemu/100/status/start",
+            403,
+            r#"{"data":null}"#,
+        ),
+    ]);
+    let config = mock_config(url);
+    let api = ProxmoxApi::new(&config, 100, ProxmoxGuest::Qemu);
+    assert!(
+        api.reboot()
+            .unwrap_err()
+            .to_string()
+            .contains("VM 100 not running")
+    );
+    assert!(api.start().unwrap_err().to_string().contains("403"));
+}
+
+#[test]
+fn test_api_upload_image() {
+    let (url, requests) = mock_server(vec![(
+        "POST /api2/json/nodes/pve/storage/images/upload",
+        200,
+        r#"{"data":null}"#,
+    )]);
+    let image = std::env::temp_dir().join(format!("snow-test-{}.tar.xz", std::process::id()));
+    std::fs::write(&image, "not really an image").unwrap();
+    let config = mock_config(url);
+    let archive = ProxmoxApi::new(&config, 100, ProxmoxGuest::Lxc(BTreeMap::new()))
+        .upload_image(&image, "snow-lxc-101.tar.xz");
+    std::fs::remove_file(&image).unwrap();
+
+    // POST /nodes/{node}/storage/{storage}/upload takes `content` (iso, vztmpl or import) and
+    // `filename`; container templates have to be tarballs
+    assert_eq!(archive.unwrap(), "images:vztmpl/snow-lxc-101.tar.xz");
+    let requests = requests.lock().unwrap();
+    assert!(
+        requests[0].contains("Content-Disposition: form-data; name=\"content\"\r\n\r\nvztmpl\r\n")
+    );
+    assert!(requests[0].contains("name=\"filename\"; filename=\"snow-lxc-101.tar.xz\""));
+    assert!(requests[0].contains("\r\n\r\nnot really an image\r\n--snow-"));
+
+    assert_eq!(
+        backup_path_command("images:backup/vzdump-qemu-100.vma.zst"),
+        "path=$(pvesm path images:backup/vzdump-qemu-100.vma.zst) && mkdir -p \"$(dirname \"$path\")\" && echo \"$path\""
+    );
+}
+
+#[test]
+fn test_api_create_container() {
+    let (url, requests) = mock_server(vec![(
+        "POST /api2/json/nodes/pve/lxc",
+        200,
+        r#"{"data":null}"#,
+    )]);
+    let config = mock_config(url);
+    let guest = ProxmoxGuest::Lxc(BTreeMap::from([(
+        "hostname".to_string(),
+        "cache".to_string(),
+    )]));
+    ProxmoxApi::new(&config, 101, guest)
+        .restore("images:vztmpl/snow-lxc-101.tar.xz", Some("local-zfs"))
+        .unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert!(requests[0].ends_with(
+        "vmid=101&ostemplate=images%3Avztmpl%2Fsnow-lxc-101.tar.xz&hostname=cache&storage=local-zfs"
+    ));
+}
+
+#[test]
+fn test_task_progress() {
+    assert_eq!(
+        task_progress("progress 45% (read 483183820 of 1073741824 bytes)"),
+        Some(45)
+    );
+    assert_eq!(
+        task_progress("progress 100% (read 1073741824 of 1073741824 bytes)"),
+        Some(100)
+    );
+    assert_eq!(
+        task_progress("transferred 1.0 GiB of 1.0 GiB (100.00%)"),
+        None
+    );
+}
@@ -0,0 +1,370 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{HOST_KEY_FW_CFG, Hypervisor, VmStatus};
+use crate::SnowError;
+use crate::commands::runners::SnowCommand;
+use crate::commands::util::{LibvirtResolved, VmHardware};
+use crate::util::Result;
+
+/// Drives a local or remote libvirt daemon through `virsh` and `virt-install`. Domains are named
+/// after their nixosConfiguration, and the uploaded image becomes the domain's disk.
+pub(crate) struct Libvirt<'a> {
+    pub(crate) config: &'a LibvirtResolved,
+    pub(crate) domain: &'a str,
+    pub(crate) hardware: &'a VmHardware,
+}
+
+impl Libvirt<'_> {
+    fn virsh(&self, args: &[&str]) -> SnowCommand {
+        let mut full_args = vec!["--connect", &self.config.uri];
+        full_args.extend_from_slice(args);
+        SnowCommand::new("virsh".to_string(), full_args, false)
+    }
+
+    /// Run a command on the libvirt host: over ssh if it is remote, with sudo otherwise, as the
+    /// image directory is usually owned by root.
+    fn on_host(&self, args: &[&str]) -> SnowCommand {
+        match self.config.host {
+            Some(ref host) => {
+                SnowCommand::new("ssh".to_string(), vec![host, &args.join(" ")], false)
+            }
+            None => SnowCommand::new(args[0].to_string(), args[1..].to_vec(), true),
+        }
+    }
+
+    fn disk_path(&self) -> String {
+        format!("{}/{}", self.config.image_dir, self.archive_name())
+    }
+
+    fn host_key_path(&self) -> String {
+        format!("{}/{}.host_key", self.config.image_dir, self.domain)
+    }
+
+    /// Copy a local file to the libvirt host, with the given permissions.
+    fn copy_to_host(&self, file: &Path, target: &str, mode: &str) -> Result<()> {
+        match self.config.host {
+            Some(ref host) => {
+                SnowCommand::new(
+                    "scp".to_string(),
+                    vec![&file.to_string_lossy(), &format!("{host}:{target}")],
+                    false,
+                )
+                .run_verbose()?;
+                self.on_host(&["chmod", mode, target]).run_silent()
+            }
+            None => self
+                .on_host(&["install", "-m", mode, &file.to_string_lossy(), target])
+                .run_verbose(),
+        }
+    }
+
+    /// (Re)define the domain from its XML description.
+    fn define(&self, xml: &str) -> Result<()> {
+        let xml_path = std::env::temp_dir().join(format!("snow-{}.xml", self.domain));
+        std::fs::write(&xml_path, xml)?;
+        let result = self
+            .virsh(&["define", &xml_path.to_string_lossy()])
+            .run_silent();
+        std::fs::remove_file(&xml_path)?;
+        result
+    }
+
+    fn inactive_xml(&self) -> Result<String> {
+        self.virsh(&["dumpxml", "--inactive", self.domain])
+            .run_with_return()
+    }
+
+    fn state(&self) -> Result<String> {
+        Ok(self
+            .virsh(&["domstate", self.domain])
+            .run_with_return()?
+            .trim()
+            .to_string())
+    }
+}
+
+impl Hypervisor for Libvirt<'_> {
+    fn image_variant(&self) -> &'static str {
+        "qemu"
+    }
+
+    fn image_extension(&self) -> &'static str {
+        ".qcow2"
+    }
+
+    fn vm_name(&self) -> String {
+        format!("domain {}", self.domain)
+    }
+
+    fn archive_name(&self) -> String {
+        format!("{}.qcow2", self.domain)
+    }
+
+    fn upload_image(&self, image: &Path, archive_name: &str) -> Result<String> {
+        let target = format!("{}/{archive_name}", self.config.image_dir);
+        log::info!("Copying the VM image to the libvirt host...");
+        // Images in the nix store are read-only, but the copy is used as a writable disk
+        self.copy_to_host(image, &target, "0644")?;
+        Ok(target)
+    }
+
+    fn remove_image(&self, _archive_name: &str) -> Result<()> {
+        // The uploaded image is the disk of the domain
+        Ok(())
+    }
+
+    fn restore(&self, archive: &str, _storage: Option<&str>) -> Result<()> {
+        let mut args = vec![
+            "--connect".to_string(),
+            self.config.uri.clone(),
+            "--name".to_string(),
+            self.domain.to_string(),
+            "--import".to_string(),
+            "--disk".to_string(),
+            format!("path={archive},bus=virtio,format=qcow2"),
+            "--os-variant".to_string(),
+            self.config.os_variant.clone(),
+            "--noautoconsole".to_string(),
+            "--print-xml".to_string(),
+        ];
+        args.extend(virt_install_hardware_args(
+            self.hardware,
+            &self.config.network,
+        ));
+        if self.hardware.network.vlan.is_some() || self.hardware.tags.is_some() {
+            log::warn!("VLAN and tags are not supported by the libvirt backend and are ignored");
+        }
+
+        let xml = SnowCommand::new(
+            "virt-install".to_string(),
+            args.iter().map(|x| x.as_str()).collect(),
+            false,
+        )
+        .run_with_return()?;
+        self.define(&xml)?;
+
+        if let Some(onboot) = self.hardware.onboot {
+            let mut args = vec!["autostart", self.domain];
+            if !onboot {
+                args.push("--disable");
+            }
+            self.virsh(&args).run_silent()?;
+        }
+        Ok(())
+    }
+
+    fn apply_hardware(&self, _hardware: &VmHardware) -> Result<()> {
+        // Already part of the domain definition created by `restore`
+        Ok(())
+    }
+
+    fn resize_disk(&self, _disk: &str, size: &str) -> Result<()> {
+        self.on_host(&["qemu-img", "resize", &self.disk_path(), size])
+            .run_silent()
+    }
+
+    fn start(&self) -> Result<()> {
+        self.virsh(&["start", self.domain]).run_silent()
+    }
+
+    fn reboot(&self) -> Result<()> {
+        self.virsh(&["reboot", self.domain]).run_silent()
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.virsh(&["shutdown", self.domain]).run_silent()?;
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(180) {
+            if self.state()? == "shut off" {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_secs(2));
+        }
+        log::warn!(
+            "{} did not shut down in time, stopping it forcefully",
+            self.domain
+        );
+        self.virsh(&["destroy", self.domain]).run_silent()
+    }
+
+    fn status(&self) -> Result<VmStatus> {
+        let output = self.virsh(&["dominfo", self.domain]).run_with_return()?;
+        let status = parse_dominfo(&output);
+        match status.status.is_empty() {
+            true => Err(SnowError::Env(format!(
+                "libvirt domain {} not found",
+                self.domain
+            ))),
+            false => Ok(status),
+        }
+    }
+
+    fn exists(&self) -> Result<bool> {
+        let domains = self.virsh(&["list", "--all", "--name"]).run_with_return()?;
+        Ok(domains.lines().any(|x| x.trim() == self.domain))
+    }
+
+    fn config(&self) -> Result<BTreeMap<String, String>> {
+        Err(SnowError::Env(
+            "reading the VM config is not supported by the libvirt backend, use `virsh edit`"
+                .to_string(),
+        ))
+    }
+
+    fn set(&self, _settings: &BTreeMap<String, String>) -> Result<()> {
+        Err(SnowError::Env(
+            "changing the VM config is not supported by the libvirt backend, use `virsh edit`"
+                .to_string(),
+        ))
+    }
+
+    fn destroy(&self) -> Result<()> {
+        self.virsh(&["undefine", self.domain, "--remove-all-storage"])
+            .run_silent()
+    }
+
+    fn inject_host_key(&self, key: &Path) -> Result<()> {
+        self.copy_to_host(key, &self.host_key_path(), "0600")?;
+        let xml = with_host_key_entry(&self.inactive_xml()?, Some(&self.host_key_path()));
+        self.define(&xml)
+    }
+
+    fn remove_host_key(&self) -> Result<()> {
+        let xml = with_host_key_entry(&self.inactive_xml()?, None);
+        self.define(&xml)?;
+        self.on_host(&["rm", "-f", &self.host_key_path()])
+            .run_silent()
+    }
+}
+
+/// Replace the fw_cfg entry carrying the host key in a domain definition, or drop it if no
+/// `file` is given.
+fn with_host_key_entry(xml: &str, file: Option<&str>) -> String {
+    let mut xml = xml.to_string();
+    if let Some(start) = xml.find(r#"<sysinfo type="fwcfg">"#)
+        && let Some(length) = xml[start..].find("</sysinfo>")
+        && xml[start..start + length].contains(HOST_KEY_FW_CFG)
+    {
+        let end = start + length + "</sysinfo>".len();
+        let line_start = xml[..start].rfind('\n').unwrap_or(start);
+        xml.replace_range(line_start..end, "");
+    }
+    if let Some(file) = file
+        && let Some(end) = xml.rfind("</domain>")
+    {
+        xml.insert_str(
+            end,
+            &format!(
+                "  <sysinfo type=\"fwcfg\">\n    <entry name=\"{HOST_KEY_FW_CFG}\" file=\"{file}\"/>\n  </sysinfo>\n"
+            ),
+        );
+    }
+    xml
+}
+
+/// `virt-install` arguments for the declared hardware settings.
+fn virt_install_hardware_args(hardware: &VmHardware, network: &str) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(memory) = hardware.memory {
+        args.extend(["--memory".to_string(), memory.to_string()]);
+    }
+    if hardware.cores.is_some() || hardware.sockets.is_some() {
+        let (cores, sockets) = (hardware.cores.unwrap_or(1), hardware.sockets.unwrap_or(1));
+        args.extend([
+            "--vcpus".to_string(),
+            format!("{},sockets={sockets},cores={cores}", cores * sockets),
+        ]);
+    }
+
+    let mut network = match hardware.network.bridge {
+        Some(ref bridge) => format!("bridge={bridge}"),
+        None => network.to_string(),
+    };
+    if let Some(ref mac) = hardware.network.mac {
+        network += &format!(",mac={}", mac.to_lowercase());
+    }
+    args.extend(["--network".to_string(), network]);
+    args
+}
+
+/// Parse the `key: value` lines printed by `virsh dominfo`.
+fn parse_dominfo(output: &str) -> VmStatus {
+    let kib = |value: &str| {
+        value
+            .trim_end_matches("KiB")
+            .trim()
+            .parse::<u64>()
+            .unwrap_or_default()
+            * 1024
+    };
+    let mut status = VmStatus::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "State" => {
+                status.status = match value {
+                    "shut off" => "stopped".to_string(),
+                    value => value.to_string(),
+                }
+            }
+            "Name" => status.name = Some(value.to_string()),
+            "CPU(s)" => status.cpus = value.parse().unwrap_or_default(),
+            "Used memory" => status.mem = kib(value),
+            "Max memory" => status.maxmem = kib(value),
+            _ => {}
+        }
+    }
+    status
+}
+
+#[test]
+fn test_libvirt_parsing() {
+    let output = "Id:             3\nName:           web\nUUID:           5d1c\nOS Type:        hvm\nState:          shut off\nCPU(s):         2\nMax memory:     4194304 KiB\nUsed memory:    4194304 KiB\nPersistent:     yes\nAutostart:      disable\n";
+    assert_eq!(
+        parse_dominfo(output),
+        VmStatus {
+            status: "stopped".to_string(),
+            name: Some("web".to_string()),
+            cpus: 2.0,
+            mem: 4294967296,
+            maxmem: 4294967296,
+            ..Default::default()
+        }
+    );
+
+    let hardware = VmHardware {
+        cores: Some(2),
+        sockets: Some(2),
+        memory: Some(4096),
+        network: crate::commands::util::VmNetwork {
+            mac: Some("BC:24:11:00:00:01".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert_eq!(
+        virt_install_hardware_args(&hardware, "network=default"),
+        vec![
+            "--memory",
+            "4096",
+            "--vcpus",
+            "4,sockets=2,cores=2",
+            "--network",
+            "network=default,mac=bc:24:11:00:00:01"
+        ]
+    );
+
+    let xml = "<domain type=\"kvm\">\n  <name>web</name>\n</domain>\n";
+    let injected = with_host_key_entry(xml, Some("/var/lib/libvirt/images/web.host_key"));
+    assert_eq!(
+        injected,
+        "<domain type=\"kvm\">\n  <name>web</name>\n  <sysinfo type=\"fwcfg\">\n    <entry name=\"opt/io.snow/ssh_host_ed25519_key\" file=\"/var/lib/libvirt/images/web.host_key\"/>\n  </sysinfo>\n</domain>\n"
+    );
+    assert_eq!(with_host_key_entry(&injected, None), xml);
+}
@@ -0,0 +1,272 @@
+mod api;
+mod libvirt;
+mod ssh;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::{VmBackendResolved, VmConfigResolved, VmHardware, VmKind, lxc_create_settings};
+use crate::{SnowError, util::Result};
+
+pub(crate) use api::ProxmoxApi;
+pub(crate) use libvirt::Libvirt;
+pub(crate) use ssh::ProxmoxSsh;
+
+/// Operations snow performs on the host running a VM, independent of which hypervisor it is and
+/// how snow talks to it.
+pub(crate) trait Hypervisor {
+    /// The `system.build.images` variant the VM is created from.
+    fn image_variant(&self) -> &'static str;
+
+    /// File extension of the image built for `image_variant`.
+    fn image_extension(&self) -> &'static str;
+
+    /// How the VM is referred to in messages, e.g. `VM 100`.
+    fn vm_name(&self) -> String;
+
+    /// Name the image is uploaded under.
+    fn archive_name(&self) -> String;
+
+    /// Make the image at `image` available to the hypervisor under `archive_name`.
+    /// Returns the reference to restore the image from.
+    fn upload_image(&self, image: &Path, archive_name: &str) -> Result<String>;
+
+    /// Remove an image previously uploaded with `upload_image`.
+    fn remove_image(&self, archive_name: &str) -> Result<()>;
+
+    /// Create the VM from an uploaded image, creating its disks on `storage` if given.
+    fn restore(&self, archive: &str, storage: Option<&str>) -> Result<()>;
+
+    /// Ids of all VMs known to the hypervisor, to avoid handing them out again.
+    fn vm_ids(&self) -> Result<Vec<usize>> {
+        Ok(vec![])
+    }
+
+    /// Create the VM as a full clone of a template, creating its disks on `storage` if given.
+    fn clone_template(
+        &self,
+        _template_id: usize,
+        _name: &str,
+        _storage: Option<&str>,
+    ) -> Result<()> {
+        Err(SnowError::Env(
+            "cloning templates is not supported by this backend".to_string(),
+        ))
+    }
+
+    /// Whether the backend manages VMs or containers.
+    fn kind(&self) -> VmKind {
+        VmKind::Qemu
+    }
+
+    /// Apply the declared hardware settings to a freshly created VM.
+    fn apply_hardware(&self, hardware: &VmHardware) -> Result<()> {
+        let settings =
+            hardware.desired_settings(self.config()?.get("net0").map(|x| x.as_str()), self.kind());
+        if !settings.is_empty() {
+            log::info!("Applying hardware settings...");
+            self.set(&settings)?;
+        }
+        Ok(())
+    }
+
+    fn resize_disk(&self, disk: &str, size: &str) -> Result<()>;
+
+    fn start(&self) -> Result<()>;
+
+    fn reboot(&self) -> Result<()>;
+
+    /// Shut the VM down gracefully, stopping it forcefully if that times out.
+    fn stop(&self) -> Result<()>;
+
+    fn status(&self) -> Result<VmStatus>;
+
+    /// Whether the VM exists. Fails only if the hypervisor could not be asked.
+    fn exists(&self) -> Result<bool>;
+
+    /// The VM's current configuration, as `qm config` reports it.
+    fn config(&self) -> Result<BTreeMap<String, String>>;
+
+    /// Update the given configuration keys of the VM.
+    fn set(&self, settings: &BTreeMap<String, String>) -> Result<()>;
+
+    /// Destroy the VM along with all of its disks. The VM has to be stopped.
+    fn destroy(&self) -> Result<()>;
+
+    /// Pass the private host key at `key` to the VM as the fw_cfg entry `HOST_KEY_FW_CFG`,
+    /// readable by it from the next boot on.
+    fn inject_host_key(&self, _key: &Path) -> Result<()> {
+        Err(SnowError::Env(
+            "injecting host keys is not supported by this backend".to_string(),
+        ))
+    }
+
+    /// Remove the host key passed with `inject_host_key` from the hypervisor.
+    fn remove_host_key(&self) -> Result<()> {
+        Err(SnowError::Env(
+            "injecting host keys is not supported by this backend".to_string(),
+        ))
+    }
+}
+
+/// Name of the fw_cfg entry the private host key is passed to the VM as.
+pub(crate) const HOST_KEY_FW_CFG: &str = "opt/io.snow/ssh_host_ed25519_key";
+
+/// The kind of guest a Proxmox backend manages.
+pub(crate) enum ProxmoxGuest {
+    Qemu,
+    /// A container, created with the given `pct create` options.
+    Lxc(BTreeMap<String, String>),
+}
+
+impl ProxmoxGuest {
+    fn kind(&self) -> VmKind {
+        match self {
+            Self::Qemu => VmKind::Qemu,
+            Self::Lxc(_) => VmKind::Lxc,
+        }
+    }
+
+    /// Guest type in API paths, and the name of the matching `pvesh` resource type.
+    fn api_type(&self) -> &'static str {
+        match self {
+            Self::Qemu => "qemu",
+            Self::Lxc(_) => "lxc",
+        }
+    }
+
+    /// Storage content type images are uploaded as.
+    fn content(&self) -> &'static str {
+        match self {
+            Self::Qemu => "backup",
+            Self::Lxc(_) => "vztmpl",
+        }
+    }
+
+    fn image_variant(&self) -> &'static str {
+        match self {
+            Self::Qemu => "proxmox",
+            Self::Lxc(_) => "proxmox-lxc",
+        }
+    }
+
+    fn image_extension(&self) -> &'static str {
+        match self {
+            Self::Qemu => ".vma.zst",
+            Self::Lxc(_) => ".tar.xz",
+        }
+    }
+
+    /// qmrestore needs vzdump names, container templates merely a known extension.
+    fn archive_name(&self, id: usize) -> String {
+        match self {
+            Self::Qemu => vzdump_archive_name(id),
+            Self::Lxc(_) => format!("snow-lxc-{id}.tar.xz"),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+pub(crate) struct VmStatus {
+    pub(crate) status: String,
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    #[serde(default)]
+    pub(crate) uptime: u64,
+    #[serde(default)]
+    pub(crate) cpu: f64,
+    #[serde(default)]
+    pub(crate) cpus: f64,
+    #[serde(default)]
+    pub(crate) mem: u64,
+    #[serde(default)]
+    pub(crate) maxmem: u64,
+}
+
+/// Format a point in time (UTC) the way vzdump names its archives: `YYYY_MM_DD-HH_MM_SS`.
+fn vzdump_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}_{month:02}_{day:02}-{:02}_{:02}_{:02}",
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// qmrestore only accepts archives following the vzdump naming scheme.
+fn vzdump_archive_name(id: usize) -> String {
+    format!(
+        "vzdump-qemu-{id}-{}.vma.zst",
+        vzdump_timestamp(SystemTime::now())
+    )
+}
+
+pub(crate) fn hypervisor<'a>(
+    vm_configuration: &'a str,
+    vm_config: &'a VmConfigResolved,
+) -> Result<Box<dyn Hypervisor + 'a>> {
+    let guest = || match vm_config.kind {
+        VmKind::Qemu => ProxmoxGuest::Qemu,
+        VmKind::Lxc => ProxmoxGuest::Lxc(lxc_create_settings(
+            vm_configuration,
+            vm_config.ip.as_deref(),
+            vm_config.prefix_length,
+            vm_config.gateway.as_deref(),
+            &vm_config.nameservers,
+            vm_config.hardware.network.bridge.as_deref(),
+        )),
+    };
+    Ok(match &vm_config.backend {
+        VmBackendResolved::Ssh {
+            id,
+            proxmox_host,
+            proxmox_image_store,
+            remote_image_dir,
+        } => Box::new(ProxmoxSsh {
+            proxmox_host,
+            proxmox_image_store,
+            remote_image_dir,
+            id: *id,
+            guest: guest(),
+        }),
+        VmBackendResolved::Api { id, config } => Box::new(ProxmoxApi::new(config, *id, guest())),
+        VmBackendResolved::Libvirt(libvirt_config) => Box::new(Libvirt {
+            config: libvirt_config,
+            domain: vm_configuration,
+            hardware: &vm_config.hardware,
+        }),
+    })
+}
+
+#[test]
+fn test_vzdump_timestamp() {
+    use std::time::Duration;
+
+    assert_eq!(
+        vzdump_timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(1717236000)),
+        "2024_06_01-10_00_00"
+    );
+    assert_eq!(
+        vzdump_timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(951868799)),
+        "2000_02_29-23_59_59"
+    );
+}
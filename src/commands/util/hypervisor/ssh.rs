@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::{HOST_KEY_FW_CFG, Hypervisor, ProxmoxGuest, VmStatus};
+use crate::SnowError;
+use crate::commands::runners::SnowCommand;
+use crate::commands::util::VmKind;
+use crate::util::Result;
+
+/// Drives Proxmox by running `qm` (or `pct`, for containers) on the host over ssh. Images are
+/// copied via a locally mounted directory of the Proxmox image storage.
+pub(crate) struct ProxmoxSsh<'a> {
+    pub(crate) proxmox_host: &'a str,
+    pub(crate) proxmox_image_store: &'a str,
+    pub(crate) remote_image_dir: &'a str,
+    pub(crate) id: usize,
+    pub(crate) guest: ProxmoxGuest,
+}
+
+impl ProxmoxSsh<'_> {
+    /// `qm` or `pct`, depending on the guest.
+    fn qm(&self, args: &str) -> SnowCommand {
+        match self.guest {
+            ProxmoxGuest::Qemu => self.ssh(&format!("qm {args}")),
+            ProxmoxGuest::Lxc(_) => self.ssh(&format!("pct {args}")),
+        }
+    }
+
+    fn ssh(&self, command: &str) -> SnowCommand {
+        SnowCommand::new("ssh".to_string(), vec![self.proxmox_host, command], false)
+    }
+}
+
+/// Where injected host keys are kept on the Proxmox host until the VM has booted.
+const REMOTE_HOST_KEY_DIR: &str = "/var/lib/snow/host-keys";
+
+impl Hypervisor for ProxmoxSsh<'_> {
+    fn image_variant(&self) -> &'static str {
+        self.guest.image_variant()
+    }
+
+    fn image_extension(&self) -> &'static str {
+        self.guest.image_extension()
+    }
+
+    fn vm_name(&self) -> String {
+        format!("VM {}", self.id)
+    }
+
+    fn archive_name(&self) -> String {
+        self.guest.archive_name(self.id)
+    }
+
+    fn kind(&self) -> VmKind {
+        self.guest.kind()
+    }
+
+    fn upload_image(&self, image: &Path, archive_name: &str) -> Result<String> {
+        let command = SnowCommand::new(
+            "cp".to_string(),
+            vec![
+                &image.to_string_lossy(),
+                &format!("{}/{archive_name}", self.proxmox_image_store),
+            ],
+            false,
+        );
+        log::info!("Copying the VM image to the proxmox host...");
+        command.run_verbose()?;
+        Ok(format!("{}/{archive_name}", self.remote_image_dir))
+    }
+
+    fn remove_image(&self, archive_name: &str) -> Result<()> {
+        std::fs::remove_file(format!("{}/{archive_name}", self.proxmox_image_store))?;
+        Ok(())
+    }
+
+    fn restore(&self, archive: &str, storage: Option<&str>) -> Result<()> {
+        if let ProxmoxGuest::Lxc(ref settings) = self.guest {
+            let mut args = format!("create {} {archive}", self.id);
+            if let Some(storage) = storage {
+                args += &format!(" --storage {storage}");
+            }
+            return self
+                .qm(&format!("{args} {}", shell_options(settings)))
+                .run_verbose();
+        }
+
+        let mut qmrestore = format!("qmrestore {archive} {} --unique true", self.id);
+        if let Some(storage) = storage {
+            qmrestore += &format!(" --storage {storage}");
+        }
+        let command = SnowCommand::new(
+            "ssh".to_string(),
+            vec![self.proxmox_host, &qmrestore],
+            false,
+        );
+        command.run_progress_import()
+    }
+
+    fn vm_ids(&self) -> Result<Vec<usize>> {
+        let output = self
+            .ssh("pvesh get /cluster/resources --type vm --output-format json")
+            .run_with_return()?;
+        Ok(serde_json::from_str::<Vec<serde_json::Value>>(&output)?
+            .iter()
+            .filter_map(|vm| Some(vm["vmid"].as_u64()? as usize))
+            .collect())
+    }
+
+    fn clone_template(&self, template_id: usize, name: &str, storage: Option<&str>) -> Result<()> {
+        let mut args = match self.guest {
+            ProxmoxGuest::Qemu => format!("clone {template_id} {} --name {name} --full 1", self.id),
+            ProxmoxGuest::Lxc(_) => {
+                format!("clone {template_id} {} --hostname {name} --full 1", self.id)
+            }
+        };
+        if let Some(storage) = storage {
+            args += &format!(" --storage {storage}");
+        }
+        self.qm(&args).run_verbose()
+    }
+
+    fn resize_disk(&self, disk: &str, size: &str) -> Result<()> {
+        match self.guest {
+            ProxmoxGuest::Qemu => self.qm(&format!("disk resize {} {disk} {size}", self.id)),
+            ProxmoxGuest::Lxc(_) => self.qm(&format!("resize {} {disk} {size}", self.id)),
+        }
+        .run_silent()
+    }
+
+    fn start(&self) -> Result<()> {
+        self.qm(&format!("start {}", self.id)).run_silent()
+    }
+
+    fn reboot(&self) -> Result<()> {
+        self.qm(&format!("reboot {}", self.id)).run_silent()
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.qm(&format!("shutdown {} --forceStop 1", self.id))
+            .run_silent()
+    }
+
+    fn status(&self) -> Result<VmStatus> {
+        let output = self
+            .qm(&format!("status {} --verbose", self.id))
+            .run_with_return()?;
+        let status = parse_qm_status(&output);
+        match status.status.is_empty() {
+            true => Err(SnowError::Proxmox(format!("VM {} not found", self.id))),
+            false => Ok(status),
+        }
+    }
+
+    fn exists(&self) -> Result<bool> {
+        Ok(self.vm_ids()?.contains(&self.id))
+    }
+
+    fn config(&self) -> Result<BTreeMap<String, String>> {
+        let output = self.qm(&format!("config {}", self.id)).run_with_return()?;
+        Ok(output
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+            .collect())
+    }
+
+    fn set(&self, settings: &BTreeMap<String, String>) -> Result<()> {
+        self.qm(&format!("set {} {}", self.id, shell_options(settings)))
+            .run_silent()
+    }
+
+    fn destroy(&self) -> Result<()> {
+        self.qm(&format!(
+            "destroy {} --purge --destroy-unreferenced-disks 1",
+            self.id
+        ))
+        .run_silent()
+    }
+
+    fn inject_host_key(&self, key: &Path) -> Result<()> {
+        let remote_key = format!("{REMOTE_HOST_KEY_DIR}/{}", self.id);
+        self.ssh(&format!("mkdir -p -m 0700 {REMOTE_HOST_KEY_DIR}"))
+            .run_silent()?;
+        // scp keeps the 0600 permissions of the local key
+        SnowCommand::new(
+            "scp".to_string(),
+            vec![
+                &key.to_string_lossy(),
+                &format!("{}:{remote_key}", self.proxmox_host),
+            ],
+            false,
+        )
+        .run_silent()?;
+        // Keep other arguments passed to QEMU, as `args` is a single setting
+        let args = self.config()?.remove("args");
+        let args = with_arg(args.as_deref(), &host_key_arg(self.id));
+        self.set(&BTreeMap::from([("args".to_string(), args)]))
+    }
+
+    fn remove_host_key(&self) -> Result<()> {
+        if let Some(args) = self.config()?.remove("args") {
+            match without_arg(&args, &host_key_arg(self.id)) {
+                Some(args) => self.set(&BTreeMap::from([("args".to_string(), args)]))?,
+                None => self
+                    .qm(&format!("set {} --delete args", self.id))
+                    .run_silent()?,
+            }
+        }
+        self.ssh(&format!("rm -f {REMOTE_HOST_KEY_DIR}/{}", self.id))
+            .run_silent()
+    }
+}
+
+/// The QEMU argument passing the injected host key of VM `id` through fw_cfg.
+fn host_key_arg(id: usize) -> String {
+    format!("-fw_cfg name={HOST_KEY_FW_CFG},file={REMOTE_HOST_KEY_DIR}/{id}")
+}
+
+/// `args` with `arg` appended, unless it is there already.
+fn with_arg(args: Option<&str>, arg: &str) -> String {
+    match args.map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        Some(args) if args.contains(arg) => args.to_string(),
+        Some(args) => format!("{args} {arg}"),
+        None => arg.to_string(),
+    }
+}
+
+/// `args` without `arg`, or `None` if nothing else is left.
+fn without_arg(args: &str, arg: &str) -> Option<String> {
+    let args = args
+        .replace(arg, "")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!args.is_empty()).then_some(args)
+}
+
+/// Format settings as options of `qm` and `pct`. Values are passed through the remote shell,
+/// and tags are separated by ';'.
+fn shell_options(settings: &BTreeMap<String, String>) -> String {
+    settings
+        .iter()
+        .map(|(key, value)| format!("--{key} '{}'", value.replace('\'', "'\\''")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse the `key: value` lines printed by `qm status --verbose` and `pct status --verbose`.
+fn parse_qm_status(output: &str) -> VmStatus {
+    let mut status = VmStatus::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "status" => status.status = value.to_string(),
+            "name" => status.name = Some(value.to_string()),
+            "uptime" => status.uptime = value.parse().unwrap_or_default(),
+            "cpu" => status.cpu = value.parse().unwrap_or_default(),
+            "cpus" => status.cpus = value.parse().unwrap_or_default(),
+            "mem" => status.mem = value.parse().unwrap_or_default(),
+            "maxmem" => status.maxmem = value.parse().unwrap_or_default(),
+            _ => {}
+        }
+    }
+    status
+}
+
+#[test]
+fn test_parse_qm_status() {
+    let output = "cpus: 2\nha:\n\tmanaged: 0\nmaxmem: 4294967296\nmem: 1073741824\nname: web\ncpu: 0.05\nstatus: running\nuptime: 3600\n";
+    assert_eq!(
+        parse_qm_status(output),
+        VmStatus {
+            status: "running".to_string(),
+            name: Some("web".to_string()),
+            uptime: 3600,
+            cpu: 0.05,
+            cpus: 2.0,
+            mem: 1073741824,
+            maxmem: 4294967296,
+        }
+    );
+}
+
+#[test]
+fn test_host_key_arg() {
+    let arg = host_key_arg(120);
+    assert_eq!(with_arg(None, &arg), arg);
+    let args = with_arg(Some("-cpu host"), &arg);
+    assert_eq!(args, format!("-cpu host {arg}"));
+    assert_eq!(with_arg(Some(&args), &arg), args);
+    assert_eq!(without_arg(&args, &arg), Some("-cpu host".to_string()));
+    assert_eq!(without_arg(&arg, &arg), None);
+}
@@ -2,7 +2,8 @@ use crate::Result;
 use kdam::{BarExt, Column, RichProgress, Spinner, term, tqdm};
 use regex::Regex;
 use std::io::{IsTerminal, stderr};
-use std::process::ExitStatus;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub(crate) struct Progress {
@@ -75,9 +76,9 @@ impl Progress {
         Ok(())
     }
 
-    pub(crate) fn cleanup(&mut self, status: ExitStatus) -> Result<()> {
+    pub(crate) fn cleanup(&mut self, success: bool) -> Result<()> {
         term::show_cursor()?;
-        if status.success() {
+        if success {
             if self.tasks_total == 0 {
                 self.tasks_total += 1;
             }
@@ -123,3 +124,60 @@ impl Progress {
             .replace(11, Column::Text(format!("[bold]{}", self.mb_disk_space)));
     }
 }
+
+/// A spinner with a changeable message, for waiting on something without measurable progress.
+pub(crate) struct WaitSpinner {
+    bar: RichProgress,
+}
+
+impl WaitSpinner {
+    pub(crate) fn new(name: &str) -> Result<Self> {
+        term::init(stderr().is_terminal());
+        term::hide_cursor()?;
+
+        Ok(Self {
+            bar: RichProgress::new(
+                tqdm!(total = 0, force_refresh = true, dynamic_ncols = true),
+                vec![
+                    Column::Spinner(Spinner::new(
+                        &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"],
+                        1.0,
+                        1.0,
+                    )),
+                    Column::Text(format!("[bold blue]{name}")),
+                    Column::Text(String::new()),
+                    Column::Text("•".to_owned()),
+                    Column::ElapsedTime,
+                ],
+            ),
+        })
+    }
+
+    pub(crate) fn set_message(&mut self, message: &str) -> Result<()> {
+        self.bar.replace(2, Column::Text(message.to_string()));
+        self.bar.refresh()?;
+        Ok(())
+    }
+
+    /// Sleep for the given duration, keeping the spinner going.
+    pub(crate) fn sleep(&mut self, duration: Duration) -> Result<()> {
+        let until = Instant::now() + duration;
+        while Instant::now() < until {
+            self.bar.refresh()?;
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn cleanup(&mut self, success: bool) -> Result<()> {
+        term::show_cursor()?;
+        match success {
+            true => self
+                .bar
+                .replace(0, Column::Text("[bold green]✔".to_owned())),
+            false => self.bar.replace(0, Column::Text("[bold red]✖".to_owned())),
+        }
+        self.bar.refresh()?;
+        Ok(())
+    }
+}
@@ -1,7 +1,29 @@
+mod age;
+mod allocation;
+mod flake_config;
+mod flake_info;
 mod helpers;
+mod host_keys;
+mod hypervisor;
 mod kdam;
 mod snow_config;
+mod ssh;
+mod state;
+mod verify;
+mod vm_hardware;
+mod wait;
 
+pub(crate) use age::*;
+pub(crate) use allocation::*;
+pub(crate) use flake_config::*;
+pub(crate) use flake_info::*;
 pub(crate) use helpers::*;
+pub(crate) use host_keys::*;
+pub(crate) use hypervisor::*;
 pub(crate) use kdam::*;
 pub(crate) use snow_config::*;
+pub(crate) use ssh::*;
+pub(crate) use state::*;
+pub(crate) use verify::*;
+pub(crate) use vm_hardware::*;
+pub(crate) use wait::*;
@@ -1,11 +1,12 @@
 use crate::SnowError;
 use crate::util::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 
-use super::read_from_repl;
+use super::{VmHardware, read_from_repl};
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SnowConfig {
     pub(crate) tags: Vec<String>,
@@ -16,11 +17,16 @@ pub(crate) struct SnowConfig {
     pub(crate) build_host: Option<String>,
     pub(crate) build_me_on: Option<String>,
     pub(crate) target_port: Option<u16>,
+    pub(crate) ssh_user: Option<String>,
+    pub(crate) ssh_identity_file: Option<String>,
+    pub(crate) ssh_jump_host: Option<String>,
+    #[serde(default)]
+    pub(crate) ssh_options: Vec<String>,
 
     pub(crate) vm: Option<VmConfig>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct VmConfig {
     pub(crate) id: Option<usize>,
@@ -28,14 +34,134 @@ pub(crate) struct VmConfig {
     pub(crate) proxmox_host: Option<String>,
     pub(crate) proxmox_image_store: Option<String>,
     pub(crate) resize_disk_to: Option<String>,
+    pub(crate) remote_image_dir: Option<String>,
+    pub(crate) target_storage: Option<String>,
+    pub(crate) disk: Option<String>,
+    pub(crate) root_partition: Option<String>,
+    #[serde(default)]
+    pub(crate) inject_host_key: bool,
+    #[serde(default)]
+    pub(crate) kind: VmKind,
+    #[serde(default)]
+    pub(crate) source: VmSourceKind,
+    pub(crate) template_id: Option<usize>,
+    pub(crate) prefix_length: Option<u8>,
+    pub(crate) gateway: Option<String>,
+    #[serde(default)]
+    pub(crate) nameservers: Vec<String>,
+    pub(crate) allocation_file: Option<String>,
+    pub(crate) allocation_name: Option<String>,
+    #[serde(flatten)]
+    pub(crate) hardware: VmHardware,
+    #[serde(default)]
+    pub(crate) backend: VmBackend,
+    #[serde(default)]
+    pub(crate) api: ProxmoxApiConfig,
+    #[serde(default)]
+    pub(crate) libvirt: LibvirtConfig,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VmKind {
+    #[default]
+    Qemu,
+    Lxc,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VmSourceKind {
+    #[default]
+    Image,
+    Template,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VmBackend {
+    #[default]
+    Ssh,
+    Api,
+    Libvirt,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProxmoxApiConfig {
+    pub(crate) url: Option<String>,
+    pub(crate) node: Option<String>,
+    pub(crate) storage: Option<String>,
+    pub(crate) token_id: Option<String>,
+    pub(crate) token_file: Option<String>,
+    #[serde(default)]
+    pub(crate) insecure: bool,
+    pub(crate) ssh_host: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LibvirtConfig {
+    pub(crate) uri: Option<String>,
+    pub(crate) host: Option<String>,
+    pub(crate) image_dir: Option<String>,
+    pub(crate) network: Option<String>,
+    pub(crate) os_variant: Option<String>,
 }
 
 pub(crate) struct VmConfigResolved {
-    pub(crate) id: usize,
-    pub(crate) ip: String,
-    pub(crate) proxmox_host: String,
-    pub(crate) proxmox_image_store: String,
-    pub(crate) resize_disk_to: String,
+    pub(crate) ip: Option<String>,
+    pub(crate) resize_disk_to: Option<String>,
+    pub(crate) target_storage: Option<String>,
+    pub(crate) disk: String,
+    pub(crate) root_partition: String,
+    pub(crate) inject_host_key: bool,
+    pub(crate) kind: VmKind,
+    pub(crate) source: VmSource,
+    pub(crate) prefix_length: u8,
+    pub(crate) gateway: Option<String>,
+    pub(crate) nameservers: Vec<String>,
+    pub(crate) hardware: VmHardware,
+    pub(crate) backend: VmBackendResolved,
+}
+
+pub(crate) enum VmSource {
+    Image,
+    Template { template_id: usize },
+}
+
+/// How the VM is managed, along with its identity there: Proxmox knows VMs by id, libvirt by
+/// domain, which is named after the nixosConfiguration.
+pub(crate) enum VmBackendResolved {
+    Ssh {
+        id: usize,
+        proxmox_host: String,
+        proxmox_image_store: String,
+        remote_image_dir: String,
+    },
+    Api {
+        id: usize,
+        config: ProxmoxApiResolved,
+    },
+    Libvirt(LibvirtResolved),
+}
+
+pub(crate) struct ProxmoxApiResolved {
+    pub(crate) url: String,
+    pub(crate) node: String,
+    pub(crate) storage: String,
+    pub(crate) token_id: String,
+    pub(crate) token_secret: String,
+    pub(crate) insecure: bool,
+    pub(crate) ssh_host: String,
+}
+
+pub(crate) struct LibvirtResolved {
+    pub(crate) uri: String,
+    pub(crate) host: Option<String>,
+    pub(crate) image_dir: String,
+    pub(crate) network: String,
+    pub(crate) os_variant: String,
 }
 
 impl SnowConfig {
@@ -52,26 +178,204 @@ impl SnowConfig {
     }
 }
 
+impl SnowConfig {
+    /// Read the snow config of the given host, along with its resolved VM settings.
+    pub(crate) fn get_vm_config(host: &str) -> Result<(Self, VmConfigResolved)> {
+        let mut snow_config = Self::get_snow_config(host)?;
+        let vm_config = match snow_config.vm.take() {
+            Some(vm_config) => vm_config.try_into()?,
+            None => {
+                return Err(SnowError::SnowConfig(format!(
+                    "VM settings are not configured for host \"{host}\""
+                )));
+            }
+        };
+        Ok((snow_config, vm_config))
+    }
+
+    /// Snow configs of all nixosConfigurations importing the snow module, keyed by host.
+    pub(crate) fn get_all_snow_configs() -> Result<BTreeMap<String, Self>> {
+        match read_from_repl(
+            "nixosConfigurations",
+            vec![
+                "--json",
+                "--apply",
+                "hosts: builtins.mapAttrs (_: host: host.config.snow or null) hosts",
+            ],
+        ) {
+            Ok(snow_configs_raw) => Ok(serde_json::from_str::<BTreeMap<String, Option<Self>>>(
+                &snow_configs_raw,
+            )?
+            .into_iter()
+            .filter_map(|(host, snow_config)| Some((host, snow_config?)))
+            .collect()),
+            Err(e) => Err(SnowError::Nix(format!(
+                "could not read snow configs of all hosts: {e}"
+            ))),
+        }
+    }
+}
+
 impl TryFrom<VmConfig> for VmConfigResolved {
     type Error = SnowError;
 
     fn try_from(value: VmConfig) -> Result<Self> {
-        Ok(Self {
-            id: value
+        let id = || {
+            value
                 .id
-                .ok_or_else(|| SnowError::SnowConfig("missing id".to_string()))?,
-            ip: value
-                .ip
-                .ok_or_else(|| SnowError::SnowConfig("missing ip".to_string()))?,
-            proxmox_host: value
-                .proxmox_host
-                .ok_or_else(|| SnowError::SnowConfig("missing proxmox_host".to_string()))?,
-            proxmox_image_store: value
-                .proxmox_image_store
-                .ok_or_else(|| SnowError::SnowConfig("missing proxmox_image_store".to_string()))?,
-            resize_disk_to: value
-                .resize_disk_to
-                .ok_or_else(|| SnowError::SnowConfig("missing resize_disk_to".to_string()))?,
+                .ok_or_else(|| SnowError::SnowConfig("missing id".to_string()))
+        };
+        let backend = match value.backend {
+            VmBackend::Ssh => VmBackendResolved::Ssh {
+                id: id()?,
+                proxmox_host: value
+                    .proxmox_host
+                    .ok_or_else(|| SnowError::SnowConfig("missing proxmox_host".to_string()))?,
+                proxmox_image_store: value.proxmox_image_store.ok_or_else(|| {
+                    SnowError::SnowConfig("missing proxmox_image_store".to_string())
+                })?,
+                remote_image_dir: value
+                    .remote_image_dir
+                    .unwrap_or_else(|| "/mnt/pve/proxmox_images/template/iso".to_string()),
+            },
+            VmBackend::Api => VmBackendResolved::Api {
+                id: id()?,
+                config: value.api.try_into()?,
+            },
+            VmBackend::Libvirt => VmBackendResolved::Libvirt(value.libvirt.into()),
+        };
+        if value.kind == VmKind::Lxc && matches!(backend, VmBackendResolved::Libvirt(_)) {
+            return Err(SnowError::SnowConfig(
+                "containers are only supported by the Proxmox backends".to_string(),
+            ));
+        }
+        Ok(Self {
+            ip: value.ip,
+            resize_disk_to: value.resize_disk_to,
+            target_storage: value.target_storage,
+            disk: value.disk.unwrap_or_else(|| match value.kind {
+                VmKind::Qemu => "virtio0".to_string(),
+                VmKind::Lxc => "rootfs".to_string(),
+            }),
+            root_partition: value
+                .root_partition
+                .unwrap_or_else(|| "/dev/vda2".to_string()),
+            inject_host_key: value.inject_host_key,
+            kind: value.kind,
+            source: match value.source {
+                VmSourceKind::Image => VmSource::Image,
+                VmSourceKind::Template => VmSource::Template {
+                    template_id: value
+                        .template_id
+                        .ok_or_else(|| SnowError::SnowConfig("missing template_id".to_string()))?,
+                },
+            },
+            prefix_length: value.prefix_length.unwrap_or(24),
+            gateway: value.gateway,
+            nameservers: value.nameservers,
+            hardware: value.hardware,
+            backend,
+        })
+    }
+}
+
+impl From<LibvirtConfig> for LibvirtResolved {
+    fn from(value: LibvirtConfig) -> Self {
+        Self {
+            uri: value.uri.unwrap_or_else(|| "qemu:///system".to_string()),
+            host: value.host,
+            image_dir: value
+                .image_dir
+                .unwrap_or_else(|| "/var/lib/libvirt/images".to_string()),
+            network: value
+                .network
+                .unwrap_or_else(|| "network=default".to_string()),
+            os_variant: value
+                .os_variant
+                .unwrap_or_else(|| "nixos-unstable".to_string()),
+        }
+    }
+}
+
+impl VmConfigResolved {
+    /// The static address of the VM, which only provisioning needs.
+    pub(crate) fn require_ip(&self) -> Result<&str> {
+        self.ip
+            .as_deref()
+            .ok_or_else(|| SnowError::SnowConfig("missing ip".to_string()))
+    }
+
+    /// The size the disk is grown to, which only provisioning needs.
+    pub(crate) fn require_resize_disk_to(&self) -> Result<&str> {
+        self.resize_disk_to
+            .as_deref()
+            .ok_or_else(|| SnowError::SnowConfig("missing resize_disk_to".to_string()))
+    }
+}
+
+/// The host part of a url like `https://pve.example.org:8006`.
+fn url_host(url: &str) -> Option<&str> {
+    let authority = url.split_once("://")?.1.split('/').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    Some(host).filter(|x| !x.is_empty())
+}
+
+impl TryFrom<ProxmoxApiConfig> for ProxmoxApiResolved {
+    type Error = SnowError;
+
+    fn try_from(value: ProxmoxApiConfig) -> Result<Self> {
+        // The secret is never part of the flake: take it from the environment, or from a file
+        // on the machine running snow.
+        let token_secret = match (std::env::var("SNOW_PROXMOX_API_TOKEN"), &value.token_file) {
+            (Ok(token_secret), _) => token_secret,
+            (Err(_), Some(token_file)) => std::fs::read_to_string(token_file)?.trim().to_string(),
+            (Err(_), None) => {
+                return Err(SnowError::SnowConfig(
+                    "missing api.tokenFile, and SNOW_PROXMOX_API_TOKEN is not set".to_string(),
+                ));
+            }
+        };
+        let url = value
+            .url
+            .ok_or_else(|| SnowError::SnowConfig("missing api.url".to_string()))?
+            .trim_end_matches('/')
+            .to_string();
+        let ssh_host = match value.ssh_host {
+            Some(ssh_host) => ssh_host,
+            None => format!(
+                "root@{}",
+                url_host(&url).ok_or_else(|| SnowError::SnowConfig(format!(
+                    "could not find the host in api.url \"{url}\", set api.sshHost"
+                )))?
+            ),
+        };
+        Ok(Self {
+            url,
+            node: value
+                .node
+                .ok_or_else(|| SnowError::SnowConfig("missing api.node".to_string()))?,
+            storage: value
+                .storage
+                .ok_or_else(|| SnowError::SnowConfig("missing api.storage".to_string()))?,
+            token_id: value
+                .token_id
+                .ok_or_else(|| SnowError::SnowConfig("missing api.tokenId".to_string()))?,
+            token_secret,
+            insecure: value.insecure,
+            ssh_host,
         })
     }
 }
+
+#[test]
+fn test_url_host() {
+    assert_eq!(
+        url_host("https://pve.example.org:8006"),
+        Some("pve.example.org")
+    );
+    assert_eq!(url_host("https://[fd00::1]:8006/"), Some("fd00::1"));
+    assert_eq!(url_host("pve.example.org"), None);
+}
@@ -0,0 +1,195 @@
+use super::{SnowConfig, repo_host_keys};
+use crate::SnowError;
+use crate::util::Result;
+use std::path::PathBuf;
+
+impl SnowConfig {
+    /// The `ssh` destination for the target host, prefixed with `sshUser` unless the configured
+    /// target host already names a user.
+    pub(crate) fn ssh_destination(&self) -> Option<String> {
+        let target_host = self.target_host.as_ref()?;
+        match &self.ssh_user {
+            Some(user) if !target_host.contains('@') => Some(format!("{user}@{target_host}")),
+            _ => Some(target_host.clone()),
+        }
+    }
+
+    /// Arguments which have to be passed to `ssh` (before the destination) to reach the target
+    /// host.
+    pub(crate) fn ssh_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(port) = self.target_port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        if let Some(ref identity_file) = self.ssh_identity_file {
+            args.push("-i".to_string());
+            args.push(identity_file.clone());
+        }
+        if let Some(ref jump_host) = self.ssh_jump_host {
+            args.push("-J".to_string());
+            args.push(jump_host.clone());
+        }
+        for option in &self.ssh_options {
+            args.push("-o".to_string());
+            args.push(option.clone());
+        }
+        args
+    }
+
+    /// `ssh_args` spelled as `-o` options only, which `scp` and `ssh-copy-id` understand as well.
+    pub(crate) fn ssh_option_args(&self) -> Vec<String> {
+        let mut options = vec![];
+        if let Some(port) = self.target_port {
+            options.push(format!("Port={port}"));
+        }
+        if let Some(ref identity_file) = self.ssh_identity_file {
+            options.push(format!("IdentityFile={identity_file}"));
+        }
+        if let Some(ref jump_host) = self.ssh_jump_host {
+            options.push(format!("ProxyJump={jump_host}"));
+        }
+        options.extend(self.ssh_options.iter().cloned());
+        options
+            .into_iter()
+            .flat_map(|option| ["-o".to_string(), option])
+            .collect()
+    }
+
+    /// Full argv for an `ssh` invocation running `remote_command` on the target host.
+    pub(crate) fn ssh_command(&self, remote_command: &[&str]) -> Option<Vec<String>> {
+        let mut args = self.ssh_args();
+        args.push(self.ssh_destination()?);
+        args.extend(remote_command.iter().map(|x| x.to_string()));
+        Some(args)
+    }
+
+    /// Full argv for an `ssh` invocation running `remote_command` on the given host. Falls back to
+    /// the host name itself (e.g. an alias from `snow ssh-config`) if no target host is configured,
+    /// and trusts the keys from `snow known-hosts` if the repository holds a key for the host.
+    pub(crate) fn ssh_command_for(&self, host: &str, remote_command: &[&str]) -> Vec<String> {
+        self.ssh_command_trusting(host, remote_command, repo_host_keys().contains_key(host))
+    }
+
+    /// Like [`Self::ssh_command_for`], but leaves it to the caller whether to trust the keys from
+    /// `snow known-hosts`, e.g. for a host being provisioned whose key is only recorded later on.
+    pub(crate) fn ssh_command_trusting(
+        &self,
+        host: &str,
+        remote_command: &[&str],
+        trust_known_hosts: bool,
+    ) -> Vec<String> {
+        let mut args = vec![];
+        if trust_known_hosts {
+            args.extend(known_hosts_args());
+        }
+        match self.ssh_command(remote_command) {
+            Some(ssh_command) => args.extend(ssh_command),
+            None => {
+                args.extend(self.ssh_args());
+                args.push(host.to_string());
+                args.extend(remote_command.iter().map(|x| x.to_string()));
+            }
+        }
+        args
+    }
+}
+
+/// ssh arguments making it verify host keys against the file generated by `snow known-hosts`.
+pub(crate) fn known_hosts_args() -> Vec<String> {
+    vec![
+        "-o".to_string(),
+        format!(
+            "UserKnownHostsFile={}",
+            snow_known_hosts_path().to_string_lossy()
+        ),
+    ]
+}
+
+/// Location of the known_hosts file generated by `snow known-hosts`.
+pub(crate) fn snow_known_hosts_path() -> PathBuf {
+    ssh_dir().join("snow_known_hosts")
+}
+
+/// Location of the ssh config include generated by `snow ssh-config`.
+pub(crate) fn snow_ssh_config_path() -> PathBuf {
+    ssh_dir().join("snow_config")
+}
+
+fn ssh_dir() -> PathBuf {
+    PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".ssh")
+}
+
+/// Split a `[user@]host` destination into its user and host parts.
+pub(crate) fn split_destination(destination: &str) -> (Option<&str>, &str) {
+    match destination.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, destination),
+    }
+}
+
+/// Append the given ssh arguments to `NIX_SSHOPTS`, keeping whatever the user already set.
+/// Returns the previous value, to be handed to `restore_nix_sshopts` once done. nix splits
+/// `NIX_SSHOPTS` at whitespace, so arguments containing any are rejected.
+pub(crate) fn extend_nix_sshopts(ssh_args: &[String]) -> Result<Option<String>> {
+    if let Some(arg) = ssh_args.iter().find(|x| x.contains(char::is_whitespace)) {
+        return Err(SnowError::SnowConfig(format!(
+            "\"{arg}\" contains whitespace, which NIX_SSHOPTS cannot carry; use a path without spaces or an option of the form Key=Value"
+        )));
+    }
+    let previous = std::env::var("NIX_SSHOPTS").ok();
+    if ssh_args.is_empty() {
+        return Ok(previous);
+    }
+    let mut opts = previous.clone().unwrap_or_default();
+    if !opts.is_empty() {
+        opts += " ";
+    }
+    opts += &ssh_args.join(" ");
+    unsafe { std::env::set_var("NIX_SSHOPTS", opts) };
+    Ok(previous)
+}
+
+pub(crate) fn restore_nix_sshopts(previous: Option<String>) {
+    match previous {
+        Some(opts) => unsafe { std::env::set_var("NIX_SSHOPTS", opts) },
+        None => unsafe { std::env::remove_var("NIX_SSHOPTS") },
+    }
+}
+
+#[test]
+fn test_ssh_args() {
+    let snow_config = SnowConfig {
+        target_host: Some("example.org".to_string()),
+        target_port: Some(2222),
+        ssh_user: Some("deploy".to_string()),
+        ssh_jump_host: Some("bastion".to_string()),
+        ssh_options: vec!["ServerAliveInterval=30".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        snow_config.ssh_command(&["true"]).unwrap(),
+        vec![
+            "-p",
+            "2222",
+            "-J",
+            "bastion",
+            "-o",
+            "ServerAliveInterval=30",
+            "deploy@example.org",
+            "true"
+        ]
+    );
+    assert_eq!(
+        snow_config.ssh_option_args(),
+        vec![
+            "-o",
+            "Port=2222",
+            "-o",
+            "ProxyJump=bastion",
+            "-o",
+            "ServerAliveInterval=30"
+        ]
+    );
+    assert!(extend_nix_sshopts(&["-i".to_string(), "/keys/my key".to_string()]).is_err());
+}
@@ -0,0 +1,15 @@
+use crate::SnowError;
+use crate::util::Result;
+use std::path::PathBuf;
+
+/// Directory for state snow keeps outside of the flake, such as the progress of a provisioning
+/// run. Follows `$XDG_STATE_HOME`, and is created if missing.
+pub(crate) fn state_dir(subdir: &str) -> Result<PathBuf> {
+    let state_home = std::env::var("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map_err(|_| SnowError::Env("neither XDG_STATE_HOME nor HOME are set".to_string()))?;
+    let dir = state_home.join("snow").join(subdir);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
@@ -0,0 +1,212 @@
+use super::{HostProbe, read_from_repl};
+use crate::SnowError;
+use crate::util::Result;
+
+/// What a host reported about the system it runs.
+#[derive(Default)]
+struct DeployedState {
+    booted_system: String,
+    current_system: String,
+    /// Where `/run/agenix` points, and the newest generation in `/run/agenix.d`.
+    agenix_generation: Option<String>,
+    latest_agenix_generation: Option<String>,
+    /// Secrets along with the file they resolve to.
+    secrets: Vec<(String, String)>,
+    missing_secrets: Vec<String>,
+    /// Errors agenix logged while activating secrets during this boot.
+    agenix_errors: Vec<String>,
+}
+
+/// Shell script reporting the deployed state of a host for `parse_deployed_state`, checking the
+/// given secrets.
+fn deployed_state_script(secrets: &[String]) -> String {
+    let mut script = "readlink -f /run/booted-system; readlink -f /run/current-system;".to_string();
+    if secrets.is_empty() {
+        return script;
+    }
+    script += " [ -e /run/agenix ] && echo \"agenix $(readlink -f /run/agenix)\";";
+    script += " echo \"latest $(ls -1d /run/agenix.d/* 2>/dev/null | sort -V | tail -n 1)\";";
+    for secret in secrets {
+        script += &format!(
+            " if [ -e '{secret}' ]; then echo \"secret {secret} $(readlink -f '{secret}')\"; else echo 'missing {secret}'; fi;"
+        );
+    }
+    script += " journalctl -b -o cat --no-pager 2>/dev/null | grep -E 'age: error|\\[agenix\\].*(WARNING|[Ee]rror)' | sed 's/^/agenix-error /';";
+    script += " true";
+    script
+}
+
+/// Parse the output of the script run by `verify_deployment`: the targets of
+/// `/run/booted-system` and `/run/current-system`, followed by what was found about secrets.
+fn parse_deployed_state(output: &str) -> DeployedState {
+    let mut lines = output.lines().map(|x| x.trim());
+    let mut state = DeployedState {
+        booted_system: lines.next().unwrap_or_default().to_string(),
+        current_system: lines.next().unwrap_or_default().to_string(),
+        ..Default::default()
+    };
+    for line in lines {
+        let Some((kind, value)) = line.split_once(' ') else {
+            continue;
+        };
+        match kind {
+            "agenix" => state.agenix_generation = Some(value.to_string()),
+            "latest" => state.latest_agenix_generation = Some(value.to_string()),
+            "missing" => state.missing_secrets.push(value.to_string()),
+            "agenix-error" => state.agenix_errors.push(value.to_string()),
+            "secret" => {
+                if let Some((secret, target)) = value.rsplit_once(' ') {
+                    state.secrets.push((secret.to_string(), target.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    state
+}
+
+/// Everything about the deployed state that is not as expected.
+fn deployment_problems(expected: &str, state: &DeployedState) -> Vec<String> {
+    let mut problems = vec![];
+    if state.booted_system != expected {
+        problems.push(format!(
+            "booted into {} instead of {expected}; the boot loader may not have picked the new generation",
+            state.booted_system
+        ));
+    }
+    if state.current_system != expected {
+        problems.push(format!(
+            "runs {} instead of {expected}",
+            state.current_system
+        ));
+    }
+    for secret in &state.missing_secrets {
+        problems.push(format!("secret {secret} was not activated"));
+    }
+    if let (Some(generation), Some(latest)) =
+        (&state.agenix_generation, &state.latest_agenix_generation)
+        && generation != latest
+    {
+        problems.push(format!(
+            "/run/agenix points to {generation} instead of the latest generation {latest}"
+        ));
+    }
+    // Secrets linked into a generation of agenix have to be from the current one, others are
+    // left over from an earlier activation
+    if let Some(generation) = &state.agenix_generation {
+        for (secret, target) in &state.secrets {
+            if target.starts_with("/run/agenix.d/")
+                && !target.starts_with(&format!("{generation}/"))
+            {
+                problems.push(format!(
+                    "secret {secret} is left over from an earlier generation ({target})"
+                ));
+            }
+        }
+    }
+    for error in &state.agenix_errors {
+        problems.push(format!("agenix failed to activate secrets: {error}"));
+    }
+    problems
+}
+
+/// Check that the host booted the system of its nixosConfiguration, and that agenix activated
+/// all of its secrets without errors.
+pub(crate) fn verify_deployment(probe: &HostProbe, nixos_configuration: &str) -> Result<()> {
+    log::info!("Verifying the system running on {}...", probe.name);
+    let expected = read_from_repl(
+        &format!("nixosConfigurations.{nixos_configuration}.config.system.build.toplevel.outPath"),
+        vec!["--raw"],
+    )?
+    .trim()
+    .to_string();
+    let secrets: Vec<String> = match read_from_repl(
+        &format!("nixosConfigurations.{nixos_configuration}.config.age.secrets"),
+        vec![
+            "--json",
+            "--apply",
+            "secrets: map (s: s.path) (builtins.attrValues secrets)",
+        ],
+    ) {
+        Ok(raw) => serde_json::from_str(&raw)?,
+        Err(e) => {
+            log::debug!("not checking secrets of {nixos_configuration}: {e}");
+            vec![]
+        }
+    };
+
+    let (success, output) = probe.run(&[&deployed_state_script(&secrets)])?;
+    if !success {
+        return Err(SnowError::Env(format!(
+            "could not inspect the system running on {}",
+            probe.name
+        )));
+    }
+
+    let problems = deployment_problems(&expected, &parse_deployed_state(&output));
+    if problems.is_empty() {
+        log::info!("{} runs {nixos_configuration} as deployed.", probe.name);
+        return Ok(());
+    }
+    for problem in &problems {
+        log::error!("{}: {problem}", probe.name);
+    }
+    Err(SnowError::Env(format!(
+        "{} did not come back as deployed",
+        probe.name
+    )))
+}
+
+#[test]
+fn test_deployment_problems() {
+    let expected = "/nix/store/abc-nixos-system-web";
+    let state =
+        parse_deployed_state("/nix/store/abc-nixos-system-web\n/nix/store/abc-nixos-system-web\n");
+    assert!(deployment_problems(expected, &state).is_empty());
+
+    let state = parse_deployed_state(
+        "/nix/store/old-nixos-system-web\n/nix/store/abc-nixos-system-web\nmissing /run/agenix/db\n",
+    );
+    assert_eq!(
+        deployment_problems(expected, &state),
+        vec![
+            "booted into /nix/store/old-nixos-system-web instead of /nix/store/abc-nixos-system-web; the boot loader may not have picked the new generation",
+            "secret /run/agenix/db was not activated"
+        ]
+    );
+}
+
+#[test]
+fn test_failed_agenix_activation() {
+    let expected = "/nix/store/abc-nixos-system-web";
+    let state = parse_deployed_state(
+        "/nix/store/abc-nixos-system-web
+/nix/store/abc-nixos-system-web
+agenix /run/agenix.d/3
+latest /run/agenix.d/3
+secret /run/agenix/db /run/agenix.d/3/db
+secret /var/lib/app/token /run/agenix.d/2/token
+agenix-error age: error: no identity matched any of the recipients
+agenix-error [agenix] WARNING: config.age.identityPaths entry /etc/ssh/ssh_host_ed25519_key not present!
+",
+    );
+    assert_eq!(
+        deployment_problems(expected, &state),
+        vec![
+            "secret /var/lib/app/token is left over from an earlier generation (/run/agenix.d/2/token)",
+            "agenix failed to activate secrets: age: error: no identity matched any of the recipients",
+            "agenix failed to activate secrets: [agenix] WARNING: config.age.identityPaths entry /etc/ssh/ssh_host_ed25519_key not present!"
+        ]
+    );
+
+    let state = parse_deployed_state(
+        "/nix/store/abc-nixos-system-web\n/nix/store/abc-nixos-system-web\nagenix /run/agenix.d/2\nlatest /run/agenix.d/3\n",
+    );
+    assert_eq!(
+        deployment_problems(expected, &state),
+        vec![
+            "/run/agenix points to /run/agenix.d/2 instead of the latest generation /run/agenix.d/3"
+        ]
+    );
+    assert!(!deployed_state_script(&[]).contains("agenix"));
+}
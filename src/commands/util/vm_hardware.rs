@@ -0,0 +1,295 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::VmKind;
+
+/// Hardware settings of a VM. Unset values are left as they are on the proxmox host.
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VmHardware {
+    pub(crate) cores: Option<u32>,
+    pub(crate) sockets: Option<u32>,
+    pub(crate) memory: Option<u32>,
+    #[serde(default)]
+    pub(crate) network: VmNetwork,
+    pub(crate) tags: Option<Vec<String>>,
+    pub(crate) onboot: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VmNetwork {
+    pub(crate) bridge: Option<String>,
+    pub(crate) vlan: Option<u16>,
+    pub(crate) mac: Option<String>,
+}
+
+/// A setting whose live value differs from the one declared in the flake.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Drift {
+    pub(crate) key: String,
+    pub(crate) live: Option<String>,
+    pub(crate) desired: String,
+}
+
+impl VmHardware {
+    /// The proxmox config values this VM should have, given its current `net0` (which carries
+    /// settings snow does not manage, such as the NIC model or firewall).
+    pub(crate) fn desired_settings(
+        &self,
+        current_net0: Option<&str>,
+        kind: VmKind,
+    ) -> BTreeMap<String, String> {
+        let mut settings = BTreeMap::new();
+        if let Some(cores) = self.cores {
+            settings.insert("cores".to_string(), cores.to_string());
+        }
+        // Containers have no notion of sockets
+        if let Some(sockets) = self.sockets
+            && kind == VmKind::Qemu
+        {
+            settings.insert("sockets".to_string(), sockets.to_string());
+        }
+        if let Some(memory) = self.memory {
+            settings.insert("memory".to_string(), memory.to_string());
+        }
+        if let Some(ref tags) = self.tags {
+            let tags = tag_set(&tags.join(";"));
+            settings.insert(
+                "tags".to_string(),
+                tags.into_iter().collect::<Vec<_>>().join(";"),
+            );
+        }
+        if let Some(onboot) = self.onboot {
+            settings.insert("onboot".to_string(), u8::from(onboot).to_string());
+        }
+        if let Some(net0) = self.network.merge_into(current_net0, kind) {
+            settings.insert("net0".to_string(), net0);
+        }
+        settings
+    }
+}
+
+impl VmNetwork {
+    /// Apply the declared network settings to a proxmox network device string such as
+    /// `virtio=BC:24:11:00:00:01,bridge=vmbr0,firewall=1`, or `name=eth0,bridge=vmbr0` for
+    /// containers. Returns `None` if nothing is declared.
+    fn merge_into(&self, current: Option<&str>, kind: VmKind) -> Option<String> {
+        if self.bridge.is_none() && self.vlan.is_none() && self.mac.is_none() {
+            return None;
+        }
+
+        let mut fields: Vec<(String, Option<String>)> = current
+            .unwrap_or(match kind {
+                VmKind::Qemu => "virtio",
+                VmKind::Lxc => "name=eth0",
+            })
+            .split(',')
+            .filter(|field| !field.is_empty())
+            .map(|field| match field.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (field.to_string(), None),
+            })
+            .collect();
+        let mut set = |key: &str, value: String| match fields.iter_mut().find(|(k, _)| k == key) {
+            Some(field) => field.1 = Some(value),
+            None => fields.push((key.to_string(), Some(value))),
+        };
+
+        if let Some(ref bridge) = self.bridge {
+            set("bridge", bridge.clone());
+        }
+        if let Some(vlan) = self.vlan {
+            set("tag", vlan.to_string());
+        }
+        // For VMs, the first field is the NIC model, carrying the MAC address as its value
+        if let Some(ref mac) = self.mac {
+            match kind {
+                VmKind::Qemu => fields[0].1 = Some(mac.to_uppercase()),
+                VmKind::Lxc => set("hwaddr", mac.to_uppercase()),
+            }
+        }
+
+        Some(
+            fields
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => format!("{key}={value}"),
+                    None => key,
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+}
+
+/// Proxmox cloud-init settings giving a cloned VM its static address.
+pub(crate) fn cloud_init_settings(
+    ip: &str,
+    prefix_length: u8,
+    gateway: Option<&str>,
+    nameservers: &[String],
+) -> BTreeMap<String, String> {
+    let mut ipconfig = format!("ip={ip}/{prefix_length}");
+    if let Some(gateway) = gateway {
+        ipconfig += &format!(",gw={gateway}");
+    }
+    let mut settings = BTreeMap::from([("ipconfig0".to_string(), ipconfig)]);
+    if !nameservers.is_empty() {
+        settings.insert("nameserver".to_string(), nameservers.join(" "));
+    }
+    settings
+}
+
+/// `pct create` options for a container: its hostname and static address (DHCP without one), and
+/// the settings a NixOS container needs to boot. Hardware settings are applied separately.
+pub(crate) fn lxc_create_settings(
+    hostname: &str,
+    ip: Option<&str>,
+    prefix_length: u8,
+    gateway: Option<&str>,
+    nameservers: &[String],
+    bridge: Option<&str>,
+) -> BTreeMap<String, String> {
+    let ip = match ip {
+        Some(ip) => format!("{ip}/{prefix_length}"),
+        None => "dhcp".to_string(),
+    };
+    let mut net0 = format!("name=eth0,bridge={},ip={ip}", bridge.unwrap_or("vmbr0"));
+    if let Some(gateway) = gateway {
+        net0 += &format!(",gw={gateway}");
+    }
+    let mut settings = BTreeMap::from([
+        ("hostname".to_string(), hostname.to_string()),
+        ("ostype".to_string(), "nixos".to_string()),
+        ("unprivileged".to_string(), "1".to_string()),
+        ("features".to_string(), "nesting=1".to_string()),
+        ("cmode".to_string(), "console".to_string()),
+        ("net0".to_string(), net0),
+    ]);
+    if !nameservers.is_empty() {
+        settings.insert("nameserver".to_string(), nameservers.join(" "));
+    }
+    settings
+}
+
+/// Compare the live config of a VM against the desired settings.
+pub(crate) fn config_drift(
+    live: &BTreeMap<String, String>,
+    desired: &BTreeMap<String, String>,
+) -> Vec<Drift> {
+    desired
+        .iter()
+        .filter(|(key, value)| match (key.as_str(), live.get(*key)) {
+            // Proxmox sorts tags and accepts several separators
+            ("tags", Some(live)) => tag_set(live) != tag_set(value),
+            (_, live) => live != Some(value),
+        })
+        .map(|(key, value)| Drift {
+            key: key.clone(),
+            live: live.get(key).cloned(),
+            desired: value.clone(),
+        })
+        .collect()
+}
+
+/// Tags as a set, split at any of the separators Proxmox accepts.
+fn tag_set(tags: &str) -> BTreeSet<String> {
+    tags.split([';', ',', ' '])
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_string())
+        .collect()
+}
+
+#[test]
+fn test_desired_settings_and_drift() {
+    let hardware = VmHardware {
+        cores: Some(4),
+        memory: Some(8192),
+        network: VmNetwork {
+            vlan: Some(20),
+            mac: Some("bc:24:11:00:00:01".to_string()),
+            ..Default::default()
+        },
+        tags: Some(vec!["web".to_string(), "prod".to_string()]),
+        onboot: Some(true),
+        ..Default::default()
+    };
+    let live = BTreeMap::from([
+        ("cores".to_string(), "4".to_string()),
+        ("memory".to_string(), "4096".to_string()),
+        (
+            "net0".to_string(),
+            "virtio=BC:24:11:AA:AA:AA,bridge=vmbr0,firewall=1".to_string(),
+        ),
+    ]);
+
+    let desired = hardware.desired_settings(live.get("net0").map(|x| x.as_str()), VmKind::Qemu);
+    assert_eq!(
+        desired.get("net0").unwrap(),
+        "virtio=BC:24:11:00:00:01,bridge=vmbr0,firewall=1,tag=20"
+    );
+    assert_eq!(
+        config_drift(&live, &desired)
+            .iter()
+            .map(|drift| drift.key.as_str())
+            .collect::<Vec<_>>(),
+        vec!["memory", "net0", "onboot", "tags"]
+    );
+    assert_eq!(desired.get("tags").unwrap(), "prod;web");
+    let tagged = BTreeMap::from([("tags".to_string(), "prod;web".to_string())]);
+    let reordered = BTreeMap::from([("tags".to_string(), "web,prod".to_string())]);
+    assert!(config_drift(&reordered, &tagged).is_empty());
+
+    let container = hardware.desired_settings(
+        Some("name=eth0,bridge=vmbr0,hwaddr=BC:24:11:AA:AA:AA,ip=10.0.0.5/24,type=veth"),
+        VmKind::Lxc,
+    );
+    assert_eq!(
+        container.get("net0").unwrap(),
+        "name=eth0,bridge=vmbr0,hwaddr=BC:24:11:00:00:01,ip=10.0.0.5/24,type=veth,tag=20"
+    );
+}
+
+#[test]
+fn test_cloud_init_settings() {
+    assert_eq!(
+        cloud_init_settings(
+            "10.0.0.5",
+            24,
+            Some("10.0.0.1"),
+            &["10.0.0.1".to_string(), "1.1.1.1".to_string()]
+        ),
+        BTreeMap::from([
+            (
+                "ipconfig0".to_string(),
+                "ip=10.0.0.5/24,gw=10.0.0.1".to_string()
+            ),
+            ("nameserver".to_string(), "10.0.0.1 1.1.1.1".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn test_lxc_create_settings() {
+    let settings = lxc_create_settings(
+        "cache",
+        Some("10.0.0.6"),
+        24,
+        Some("10.0.0.1"),
+        &["10.0.0.1".to_string()],
+        None,
+    );
+    assert_eq!(
+        settings.get("net0").unwrap(),
+        "name=eth0,bridge=vmbr0,ip=10.0.0.6/24,gw=10.0.0.1"
+    );
+    assert_eq!(settings.get("ostype").unwrap(), "nixos");
+    assert_eq!(settings.get("nameserver").unwrap(), "10.0.0.1");
+    assert_eq!(
+        lxc_create_settings("cache", None, 24, None, &[], Some("vmbr1"))
+            .get("net0")
+            .unwrap(),
+        "name=eth0,bridge=vmbr1,ip=dhcp"
+    );
+}
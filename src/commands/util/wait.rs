@@ -0,0 +1,205 @@
+use std::io::{BufRead, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use strum::Display;
+
+use super::WaitSpinner;
+use crate::SnowError;
+use crate::commands::runners::SnowCommand;
+use crate::util::Result;
+
+/// How far a host has to have come up before waiting for it ends. Stages are checked in order.
+#[derive(Display, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum WaitStage {
+    #[strum(serialize = "TCP port open")]
+    Tcp,
+    #[strum(serialize = "SSH banner")]
+    Banner,
+    #[strum(serialize = "SSH login")]
+    Login,
+    #[strum(serialize = "system running")]
+    SystemRunning,
+}
+
+/// How long to wait for hosts, and whether they have to finish booting.
+#[derive(Clone, Copy)]
+pub(crate) struct WaitOptions {
+    pub(crate) timeout: Duration,
+    pub(crate) system_running: bool,
+}
+
+impl WaitOptions {
+    /// The stage after which a host counts as up.
+    pub(crate) fn ready_stage(&self) -> WaitStage {
+        match self.system_running {
+            true => WaitStage::SystemRunning,
+            false => WaitStage::Login,
+        }
+    }
+}
+
+/// Probes a host over ssh. `ssh_args` are the arguments to `ssh` up to and including the
+/// destination.
+pub(crate) struct HostProbe<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) ssh_args: Vec<String>,
+    pub(crate) options: WaitOptions,
+}
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl HostProbe<'_> {
+    fn ssh(&self, remote_command: &[&str]) -> SnowCommand {
+        let mut args = vec!["-o", "BatchMode=yes", "-o", "ConnectTimeout=5"];
+        args.extend(self.ssh_args.iter().map(|x| x.as_str()));
+        args.extend_from_slice(remote_command);
+        SnowCommand::new("ssh".to_string(), args, false)
+    }
+
+    /// Run a command on the host, returning whether it succeeded along with its output.
+    pub(crate) fn run(&self, remote_command: &[&str]) -> Result<(bool, String)> {
+        self.ssh(remote_command).run_with_status()
+    }
+
+    /// The current boot id of the host, if it can be logged into.
+    pub(crate) fn boot_id(&self) -> Option<String> {
+        match self
+            .ssh(&["cat", "/proc/sys/kernel/random/boot_id"])
+            .run_with_status()
+        {
+            Ok((true, boot_id)) => Some(boot_id.trim().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Address ssh connects to, unless it goes through a jump host or proxy command.
+    fn direct_address(&self) -> Option<(String, u16)> {
+        let mut args = vec!["-G"];
+        args.extend(self.ssh_args.iter().map(|x| x.as_str()));
+        let (success, output) = SnowCommand::new("ssh".to_string(), args, false)
+            .run_with_status()
+            .ok()?;
+        if !success {
+            return None;
+        }
+        parse_ssh_g(&output)
+    }
+
+    /// Wait until the host has reached `until`. If `previous_boot_id` is given, logging in only
+    /// counts once the host reports a different one, i.e. once it has actually rebooted.
+    pub(crate) fn wait(&self, until: WaitStage, previous_boot_id: Option<&str>) -> Result<()> {
+        let mut spinner = WaitSpinner::new(self.name)?;
+        let result = self.wait_with(&mut spinner, until, previous_boot_id);
+        spinner.cleanup(result.is_ok())?;
+        result
+    }
+
+    fn wait_with(
+        &self,
+        spinner: &mut WaitSpinner,
+        until: WaitStage,
+        previous_boot_id: Option<&str>,
+    ) -> Result<()> {
+        let deadline = Instant::now() + self.options.timeout;
+        let address = self.direct_address();
+        if address.is_none() {
+            log::debug!(
+                "{} is not reached directly, only probing ssh logins",
+                self.name
+            );
+        }
+
+        let stages = [
+            WaitStage::Tcp,
+            WaitStage::Banner,
+            WaitStage::Login,
+            WaitStage::SystemRunning,
+        ];
+        for stage in stages.into_iter().filter(|stage| *stage <= until) {
+            spinner.set_message(&format!("waiting for {stage}"))?;
+            loop {
+                let reached = match (stage, &address) {
+                    (WaitStage::Tcp | WaitStage::Banner, None) => true,
+                    (WaitStage::Tcp, Some(address)) => connect(address).is_some(),
+                    (WaitStage::Banner, Some(address)) => read_banner(address).is_some(),
+                    (WaitStage::Login, _) => self
+                        .boot_id()
+                        .is_some_and(|boot_id| Some(boot_id.as_str()) != previous_boot_id),
+                    (WaitStage::SystemRunning, _) => self.system_running()?,
+                };
+                if reached {
+                    log::debug!("{} reached stage \"{stage}\"", self.name);
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(SnowError::Env(format!(
+                        "timed out after {}s waiting for {} ({stage})",
+                        self.options.timeout.as_secs(),
+                        self.name
+                    )));
+                }
+                spinner.sleep(RETRY_INTERVAL)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn system_running(&self) -> Result<bool> {
+        let (_, state) = self
+            .ssh(&["systemctl", "is-system-running"])
+            .run_with_status()?;
+        match state.trim() {
+            "running" => Ok(true),
+            "degraded" => {
+                log::warn!("{} booted, but some units failed", self.name);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+fn connect((host, port): &(String, u16)) -> Option<TcpStream> {
+    let address = (host.as_str(), *port).to_socket_addrs().ok()?.next()?;
+    TcpStream::connect_timeout(&address, ATTEMPT_TIMEOUT).ok()
+}
+
+fn read_banner(address: &(String, u16)) -> Option<String> {
+    let stream = connect(address)?;
+    stream.set_read_timeout(Some(ATTEMPT_TIMEOUT)).ok()?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).ok()?;
+    line.starts_with("SSH-").then(|| line.trim().to_string())
+}
+
+/// Extract host and port from the configuration printed by `ssh -G`. `None` if connections go
+/// through a jump host or proxy command, as the host may not be reachable directly then.
+fn parse_ssh_g(output: &str) -> Option<(String, u16)> {
+    let (mut host, mut port) = (None, 22);
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+            continue;
+        };
+        match key {
+            "hostname" => host = Some(value.to_string()),
+            "port" => port = value.parse().ok()?,
+            "proxyjump" | "proxycommand" if value != "none" => return None,
+            _ => {}
+        }
+    }
+    Some((host?, port))
+}
+
+#[test]
+fn test_parse_ssh_g() {
+    assert_eq!(
+        parse_ssh_g("user deploy\nhostname 10.0.0.5\nport 2222\nproxycommand none\n"),
+        Some(("10.0.0.5".to_string(), 2222))
+    );
+    assert_eq!(
+        parse_ssh_g("hostname 10.0.0.5\nport 22\nproxyjump bastion\n"),
+        None
+    );
+}
@@ -0,0 +1,158 @@
+use inquire::{Confirm, Text};
+
+use crate::{SnowError, util::Result};
+
+use super::util::{SnowConfig, VmStatus, config_drift, hypervisor};
+
+pub(crate) fn vm_start(vm_configuration: &str) -> Result<()> {
+    let (_, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    log::info!("Starting {vm_configuration}...");
+    hypervisor(vm_configuration, &vm_config)?.start()
+}
+
+pub(crate) fn vm_stop(vm_configuration: &str) -> Result<()> {
+    let (_, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    log::info!("Shutting down {vm_configuration}...");
+    hypervisor(vm_configuration, &vm_config)?.stop()
+}
+
+pub(crate) fn vm_reboot(vm_configuration: &str) -> Result<()> {
+    let (_, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    log::info!("Rebooting {vm_configuration}...");
+    hypervisor(vm_configuration, &vm_config)?.reboot()
+}
+
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    match days {
+        0 => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+fn format_status(vm_configuration: &str, vm_name: &str, status: &VmStatus) -> Vec<String> {
+    let gib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+    let mut lines = vec![format!(
+        "{vm_configuration} ({vm_name}{}): {}",
+        status
+            .name
+            .as_ref()
+            .map(|name| format!(", \"{name}\""))
+            .unwrap_or_default(),
+        status.status
+    )];
+    if status.status == "running" {
+        lines.push(format!("  uptime: {}", format_uptime(status.uptime)));
+        lines.push(format!(
+            "  cpu:    {:.1}% of {} cores",
+            status.cpu * 100.0,
+            status.cpus
+        ));
+        lines.push(format!(
+            "  memory: {:.1} GiB / {:.1} GiB",
+            gib(status.mem),
+            gib(status.maxmem)
+        ));
+    }
+    lines
+}
+
+pub(crate) fn vm_status(vm_configuration: &str) -> Result<()> {
+    let (_, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    let hypervisor = hypervisor(vm_configuration, &vm_config)?;
+    let status = hypervisor.status()?;
+    for line in format_status(vm_configuration, &hypervisor.vm_name(), &status) {
+        log::info!("{line}");
+    }
+    Ok(())
+}
+
+/// Bring the live hardware settings of the VM in line with those declared in the flake.
+pub(crate) fn vm_reconcile(vm_configuration: &str, yes: bool) -> Result<()> {
+    let (_, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    let hypervisor = hypervisor(vm_configuration, &vm_config)?;
+
+    let live = hypervisor.config()?;
+    let desired = vm_config
+        .hardware
+        .desired_settings(live.get("net0").map(|x| x.as_str()), vm_config.kind);
+    let drift = config_drift(&live, &desired);
+    if drift.is_empty() {
+        log::info!("{vm_configuration} matches its declared hardware settings.");
+        return Ok(());
+    }
+
+    log::info!("{vm_configuration} has drifted from its declared hardware settings:");
+    for entry in &drift {
+        log::info!(
+            "  {}: {} -> {}",
+            entry.key,
+            entry.live.as_deref().unwrap_or("[unset]"),
+            entry.desired
+        );
+    }
+    if !yes
+        && !Confirm::new("Apply the declared settings?")
+            .with_default(false)
+            .prompt()
+            .is_ok_and(|x| x)
+    {
+        return Ok(());
+    }
+
+    let settings = drift
+        .into_iter()
+        .map(|entry| (entry.key, entry.desired))
+        .collect();
+    hypervisor.set(&settings)?;
+    if hypervisor.status()?.status == "running" {
+        log::info!("Some changes only take effect once {vm_configuration} has been rebooted.");
+    }
+    Ok(())
+}
+
+pub(crate) fn vm_destroy(vm_configuration: &str) -> Result<()> {
+    let (_, vm_config) = SnowConfig::get_vm_config(vm_configuration)?;
+    let hypervisor = hypervisor(vm_configuration, &vm_config)?;
+
+    let answer = Text::new(&format!(
+        "This will irrevocably destroy {} and all of its disks. Type \"{vm_configuration}\" to confirm:",
+        hypervisor.vm_name()
+    ))
+    .prompt()
+    .map_err(|_| SnowError::Env("prompt cancelled".to_string()))?;
+    if answer.trim() != vm_configuration {
+        return Err(SnowError::Env(
+            "confirmation did not match, nothing was destroyed".to_string(),
+        ));
+    }
+
+    if hypervisor.status()?.status != "stopped" {
+        log::info!("Shutting down {vm_configuration}...");
+        hypervisor.stop()?;
+    }
+    log::info!("Destroying {vm_configuration}...");
+    hypervisor.destroy()
+}
+
+#[test]
+fn test_format_status() {
+    let status = VmStatus {
+        status: "running".to_string(),
+        name: Some("web".to_string()),
+        uptime: 93780,
+        cpu: 0.125,
+        cpus: 2.0,
+        mem: 1073741824,
+        maxmem: 4294967296,
+    };
+    assert_eq!(
+        format_status("web", "VM 100", &status),
+        vec![
+            "web (VM 100, \"web\"): running",
+            "  uptime: 1d 2h 3m",
+            "  cpu:    12.5% of 2 cores",
+            "  memory: 1.0 GiB / 4.0 GiB"
+        ]
+    );
+}
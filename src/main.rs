@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use clap_complete::CompleteEnv;
 use log::LevelFilter;
 use std::sync::OnceLock;
 
@@ -11,6 +12,7 @@ use options::*;
 static LOG_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
 
 fn main() {
+    CompleteEnv::with_factory(Args::command).complete();
     let args = Args::parse();
 
     LOG_LEVEL.get_or_init(|| {
@@ -47,7 +49,38 @@ fn main() {
             vm_configuration,
             login_after_setup,
             rebuild_host_machine,
-        } => provision(vm_configuration, *login_after_setup, *rebuild_host_machine),
+            resume,
+            from_step,
+            wait_timeout,
+            wait_for_system,
+        } => provision(
+            vm_configuration,
+            *login_after_setup,
+            *rebuild_host_machine,
+            *resume,
+            *from_step,
+            *wait_timeout,
+            *wait_for_system,
+        ),
+        Commands::Deprovision {
+            vm_configuration,
+            yes,
+            rekey_only,
+        } => deprovision(vm_configuration, *yes, *rekey_only),
+        Commands::New { subcommand } => match subcommand {
+            NewSubcommands::Host { name, target } => new_host(name, target),
+        },
+        Commands::Vm { subcommand } => match subcommand {
+            VmSubcommands::Start { vm_configuration } => vm_start(vm_configuration),
+            VmSubcommands::Stop { vm_configuration } => vm_stop(vm_configuration),
+            VmSubcommands::Reboot { vm_configuration } => vm_reboot(vm_configuration),
+            VmSubcommands::Status { vm_configuration } => vm_status(vm_configuration),
+            VmSubcommands::Reconcile {
+                vm_configuration,
+                yes,
+            } => vm_reconcile(vm_configuration, *yes),
+            VmSubcommands::Destroy { vm_configuration } => vm_destroy(vm_configuration),
+        },
         Commands::Hash { output } => build(output, true),
         Commands::Build { output } => build(output, false),
         Commands::Run { output } => run(output),
@@ -66,6 +99,7 @@ fn main() {
             GitSubcommands::Init => git_init(*submodules_only),
         },
         Commands::Agenix { subcommand } => match subcommand {
+            AgenixSubcommands::List => agenix_list(),
             AgenixSubcommands::UpdateMasterkeys => agenix_update_masterkeys(),
             AgenixSubcommands::Edit { file } => agenix_edit(file),
             AgenixSubcommands::Rekey { force, dummy } => agenix_rekey(*force, *dummy),
@@ -83,20 +117,49 @@ fn main() {
         Commands::Bump { subcommand } => match subcommand {
             BumpSubcommands::Python { version } => bump_python(version),
         },
+        Commands::X { name, args } => custom(name, args),
+        Commands::Completions { shell } => completions(shell),
+        Commands::Ssh {
+            nixos_configuration,
+            command,
+        } => ssh_login(nixos_configuration, command),
+        Commands::Exec { targets, command } => exec(targets, command),
+        Commands::SshConfig { output } => ssh_config(output),
+        Commands::KnownHosts { output } => known_hosts(output),
         Commands::Debug {
             nixos_configuration,
         } => debug_build(nixos_configuration),
         Commands::Assimilate {
             prepare,
+            authorized_key,
             target,
             nixos_configuration,
+            create,
+            hardware_report,
+            install,
+            kexec_url,
+            wait_timeout,
+            wait_for_system,
         } => {
             if *prepare {
-                assimilate_prepare()
+                assimilate_prepare(authorized_key.as_deref())
+            } else if *install {
+                assimilate_install(
+                    target.as_deref().unwrap(),
+                    nixos_configuration.as_deref().unwrap(),
+                    *create,
+                    kexec_url.as_deref(),
+                    *wait_timeout,
+                    *wait_for_system,
+                )
             } else {
                 assimilate_run(
                     target.as_deref().unwrap(),
                     nixos_configuration.as_deref().unwrap(),
+                    *create,
+                    *hardware_report,
+                    *wait_timeout,
+                    *wait_for_system,
                 )
             }
         }
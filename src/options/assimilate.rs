@@ -0,0 +1,15 @@
+use clap::ValueEnum;
+use strum::Display;
+
+/// How `snow assimilate` captures the hardware of the target.
+#[derive(ValueEnum, Debug, Display, Clone, Copy, PartialEq)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum HardwareReport {
+    /// hardware-configuration.nix from `nixos-generate-config --show-hardware-config`.
+    GenerateConfig,
+    /// facter.json from `nixos-facter`.
+    Facter,
+    /// Both of the above.
+    Both,
+}
@@ -0,0 +1,11 @@
+use clap::ValueEnum;
+use strum::Display;
+
+#[derive(ValueEnum, Debug, Display, Clone)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
@@ -1,3 +1,9 @@
+mod assimilate;
+mod completions;
+mod provision;
 mod rebuild;
 
+pub(crate) use assimilate::HardwareReport;
+pub(crate) use completions::CompletionShell;
+pub(crate) use provision::ProvisionStep;
 pub(crate) use rebuild::RebuildMode;
@@ -0,0 +1,42 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+/// The steps of `snow provision`, in the order they are run.
+#[derive(
+    ValueEnum,
+    Debug,
+    Display,
+    EnumIter,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[clap(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum ProvisionStep {
+    DummyRekey,
+    GenerateKey,
+    Build,
+    Upload,
+    Restore,
+    Clone,
+    Configure,
+    InjectKey,
+    Cleanup,
+    Resize,
+    Start,
+    Keyscan,
+    Rekey,
+    Rebuild,
+    Reboot,
+    ResizeFs,
+    RemoveKey,
+    Verify,
+}
@@ -1,5 +1,10 @@
-use crate::RebuildMode;
+use crate::commands::{
+    complete_dev_shells, complete_home_configurations, complete_hosts, complete_hosts_and_tags,
+    complete_secrets,
+};
+use crate::{CompletionShell, HardwareReport, ProvisionStep, RebuildMode};
 use clap::{Parser, Subcommand};
+use clap_complete::ArgValueCandidates;
 
 /// CLI wrapper for all commonly used nix, git and agenix commands, as well as a bunch of useful
 /// helper scripts.
@@ -46,11 +51,18 @@ pub(crate) enum GitSubcommands {
 
 #[derive(Subcommand, Debug)]
 pub(crate) enum AgenixSubcommands {
+    /// List all secrets with the hosts and keys they are encrypted for. Flags secrets encrypted
+    /// for the wrong recipients, for dummy keys, or used by no host.
+    List,
+
     /// Update all secrets with a new set of masterkeys.
     UpdateMasterkeys,
 
     /// Edit the given secret.
-    Edit { file: String },
+    Edit {
+        #[arg(add = ArgValueCandidates::new(complete_secrets))]
+        file: String,
+    },
 
     /// Rekey all secrets for the hosts requiring them.
     Rekey {
@@ -70,10 +82,66 @@ pub(crate) enum BumpSubcommands {
     Python { version: String },
 }
 
+#[derive(Subcommand, Debug)]
+pub(crate) enum NewSubcommands {
+    /// Create hosts/<name>/default.nix from the host template of the flake, and add the host
+    /// to nixosConfigurations.
+    Host {
+        name: String,
+
+        /// Target in user@host format, set as snow.targetHost and snow.sshUser.
+        target: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum VmSubcommands {
+    /// Start the VM.
+    Start {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        vm_configuration: String,
+    },
+
+    /// Shut the VM down, forcefully stopping it if it does not react.
+    Stop {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        vm_configuration: String,
+    },
+
+    /// Reboot the VM.
+    Reboot {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        vm_configuration: String,
+    },
+
+    /// Show power state, uptime and resource usage of the VM.
+    Status {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        vm_configuration: String,
+    },
+
+    /// Compare the VM's hardware settings against the flake and fix any drift.
+    Reconcile {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        vm_configuration: String,
+
+        /// Apply the declared settings without asking for confirmation.
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Destroy the VM and all of its disks. Requires typing the VM's name to confirm.
+    Destroy {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        vm_configuration: String,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 pub(crate) enum Commands {
     /// Rebuild the config for a given host, defaulting to the current host.
     Rebuild {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
         nixos_configuration: Option<String>,
 
         /// Rebuild mode.
@@ -113,10 +181,14 @@ pub(crate) enum Commands {
     },
 
     /// Rebuild only the HomeManager config for the current user and host.
-    Home { home_configuration: Option<String> },
+    Home {
+        #[arg(add = ArgValueCandidates::new(complete_home_configurations))]
+        home_configuration: Option<String>,
+    },
 
-    /// Create a new virtual machine and import it in Proxmox.
+    /// Create a new virtual machine and import it in Proxmox or libvirt.
     Provision {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
         vm_configuration: String,
 
         /// Whether to SSH into the newly created VM after setup is complete.
@@ -127,6 +199,51 @@ pub(crate) enum Commands {
         /// available for use in the terminal.
         #[arg(long, short)]
         rebuild_host_machine: bool,
+
+        /// Continue a failed provisioning run after its last completed step.
+        #[arg(long, conflicts_with = "from_step")]
+        resume: bool,
+
+        /// Start provisioning at the given step, skipping all steps before it.
+        #[arg(long)]
+        from_step: Option<ProvisionStep>,
+
+        /// Seconds to wait for the machine to come up after each boot.
+        #[arg(long, default_value_t = 300)]
+        wait_timeout: u64,
+
+        /// After each boot, also wait for `systemctl is-system-running` to report the system as
+        /// up.
+        #[arg(long)]
+        wait_for_system: bool,
+    },
+
+    /// Retire a VM: destroy it, remove its host key and clean up known_hosts. Once it is removed
+    /// from nixosConfigurations, run again with --rekey-only to rekey secrets without it. Every
+    /// step asks for confirmation.
+    Deprovision {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        vm_configuration: String,
+
+        /// Perform all steps without asking for confirmation.
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Only rekey secrets without the VM, after it has been removed from the flake.
+        #[arg(long)]
+        rekey_only: bool,
+    },
+
+    /// Scaffold new parts of the flake.
+    New {
+        #[command(subcommand)]
+        subcommand: NewSubcommands,
+    },
+
+    /// Manage the lifecycle of VMs configured through snow.vm.
+    Vm {
+        #[command(subcommand)]
+        subcommand: VmSubcommands,
     },
 
     /// Print just the missing hash of a build process.
@@ -139,7 +256,10 @@ pub(crate) enum Commands {
     Run { output: Option<String> },
 
     /// Enter the default shell specified in the current flake.nix, or the shell specified.
-    Develop { shell_name: Option<String> },
+    Develop {
+        #[arg(add = ArgValueCandidates::new(complete_dev_shells))]
+        shell_name: Option<String>,
+    },
 
     /// Enter a nix shell with the given packages installed.
     Shell { packages: Vec<String> },
@@ -201,8 +321,67 @@ pub(crate) enum Commands {
         subcommand: BumpSubcommands,
     },
 
+    /// Run a custom command defined in the flake's `snow.commands` output. Lists all available
+    /// commands if no name is given.
+    ///
+    /// Each command is an attribute set with the fields `command` (the command line to run),
+    /// `description`, `args` (arguments always passed before the ones given here), `sudo` and
+    /// `output` (one of "progress", "silent" or "verbose").
+    X {
+        name: Option<String>,
+
+        /// Arguments passed on to the command.
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
     /// Find the reason for a warning when building the given nixosConfiguration.
-    Debug { nixos_configuration: Option<String> },
+    Debug {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        nixos_configuration: Option<String>,
+    },
+
+    /// Open an SSH session on the given host, using the connection settings from its snow config.
+    Ssh {
+        #[arg(add = ArgValueCandidates::new(complete_hosts))]
+        nixos_configuration: String,
+
+        /// Command to run instead of a login shell.
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    /// Run a command on one or more hosts in parallel. Pass @<tag> to target all hosts carrying
+    /// that tag.
+    ///
+    /// Example: snow exec @web db -- systemctl is-system-running
+    Exec {
+        #[arg(required = true, add = ArgValueCandidates::new(complete_hosts_and_tags))]
+        targets: Vec<String>,
+
+        /// Command to run on every host.
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Generate an ssh config include with a Host block for every nixosConfiguration.
+    SshConfig {
+        /// Where to write the config. Defaults to ~/.ssh/snow_config.
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+
+    /// Generate a known_hosts file from the host public keys stored in the repository.
+    KnownHosts {
+        /// Where to write the known_hosts file. Defaults to ~/.ssh/snow_known_hosts.
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+
+    /// Print the script enabling shell completions for snow.
+    ///
+    /// For example, add `snow completions fish | source` to your fish config.
+    Completions { shell: CompletionShell },
 
     /// Bootstrap a fresh NixOS installation: copy keys, generate hardware config, deploy, reboot.
     ///
@@ -210,12 +389,19 @@ pub(crate) enum Commands {
     /// without --prepare from the managing host to complete the assimilation.
     /// Without snow on the target:
     ///   nix --extra-experimental-features 'nix-command flakes' run github:charludo/snow -- assimilate --prepare
+    ///
+    /// Machines running any other Linux are installed from scratch with --install instead.
     Assimilate {
         /// Enable SSH, trusted-users, and flakes on this machine via a temporary nixos-rebuild.
         /// Run this on the fresh target before invoking assimilate from the managing host.
         #[arg(long, conflicts_with_all = ["target", "nixos_configuration"])]
         prepare: bool,
 
+        /// With --prepare, authorize this public key (or the key in this file) for the current
+        /// user and keep password logins disabled.
+        #[arg(long, requires = "prepare")]
+        authorized_key: Option<String>,
+
         /// Target in user@host format.
         #[arg(required_unless_present = "prepare")]
         target: Option<String>,
@@ -223,5 +409,33 @@ pub(crate) enum Commands {
         /// nixosConfiguration to deploy.
         #[arg(required_unless_present = "prepare")]
         nixos_configuration: Option<String>,
+
+        /// Create the nixosConfiguration first, as `snow new host` does.
+        #[arg(long, conflicts_with = "prepare")]
+        create: bool,
+
+        /// How to capture the hardware of the target: as hardware-configuration.nix, as a
+        /// nixos-facter report in facter.json, or both.
+        #[arg(long, value_enum, default_value_t = HardwareReport::GenerateConfig, conflicts_with_all = ["prepare", "install"])]
+        hardware_report: HardwareReport,
+
+        /// Install on any Linux machine instead of converting an existing NixOS: kexec into a
+        /// NixOS installer, partition the disks with the host's disko config and run
+        /// nixos-install. Erases the target's disks.
+        #[arg(long, conflicts_with = "prepare")]
+        install: bool,
+
+        /// Tarball of the kexec installer to boot, instead of the latest nixos-images release.
+        #[arg(long, requires = "install")]
+        kexec_url: Option<String>,
+
+        /// Seconds to wait for the machine to come up after each boot.
+        #[arg(long, default_value_t = 300)]
+        wait_timeout: u64,
+
+        /// After each boot, also wait for `systemctl is-system-running` to report the system as
+        /// up.
+        #[arg(long)]
+        wait_for_system: bool,
     },
 }
@@ -7,6 +7,7 @@ pub(crate) enum SnowError {
     Nix(String),
     Env(String),
     SnowConfig(String),
+    Proxmox(String),
     IO(std::io::Error),
 }
 
@@ -19,6 +20,7 @@ impl Display for SnowError {
                 SnowError::Nix(e) => format!("Nix command failed with error: {e}"),
                 SnowError::Env(e) => format!("Environment error: {e}"),
                 SnowError::SnowConfig(e) => format!("Error parsing snow config: {e}"),
+                SnowError::Proxmox(e) => format!("Proxmox request failed: {e}"),
                 SnowError::IO(e) => format!("Error in interaction with shell: {e}"),
             }
         )
@@ -33,6 +35,12 @@ impl From<serde_json::Error> for SnowError {
     }
 }
 
+impl From<ureq::Error> for SnowError {
+    fn from(value: ureq::Error) -> Self {
+        SnowError::Proxmox(value.to_string())
+    }
+}
+
 impl From<std::io::Error> for SnowError {
     fn from(value: std::io::Error) -> Self {
         SnowError::IO(value)
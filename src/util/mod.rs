@@ -2,7 +2,10 @@ mod args;
 mod error_handling;
 mod logging;
 
-pub(super) use args::{AgenixSubcommands, Args, BumpSubcommands, Commands, GitSubcommands};
+pub(super) use args::{
+    AgenixSubcommands, Args, BumpSubcommands, Commands, GitSubcommands, NewSubcommands,
+    VmSubcommands,
+};
 pub(super) use error_handling::{Result, SnowError};
 pub(super) use logging::setup_logger;
 